@@ -32,6 +32,19 @@ pub mod rpc;
 struct ExactNS {
     unique: UniqueFile,
     source: NSSource,
+    /// Recorded when the NS was added. Older entries have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fp: Option<Fingerprint>,
+}
+
+/// Tells an NS, or the process anchoring it, from a later one that reuses the inode or the PID
+#[public]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Fingerprint {
+    /// Start time of the process, in clock ticks since boot
+    start: Option<u64>,
+    /// Cookie of the NetNS, which is not reused within a boot
+    cookie: Option<u64>,
 }
 
 impl Display for ExactNS {
@@ -158,6 +171,7 @@ impl NSFrom<PathBuf> for ExactNS {
         Ok(Self {
             unique: stat.into(),
             source: NSSource::Path(path),
+            fp: None,
         })
     }
 }
@@ -178,10 +192,90 @@ impl NSFrom<pid_t> for ExactNS {
         Ok(Self {
             unique: pidfd_uf(source)?.into(),
             source: NSSource::Pid(source),
+            fp: None,
         })
     }
 }
 
+impl ExactNS {
+    /// Process the NS is reached through, if any
+    pub fn anchor(&self) -> Option<pid_t> {
+        match &self.source {
+            NSSource::Pid(p) => Some(*p),
+            NSSource::Path(p) => {
+                let mut comps = p.strip_prefix("/proc/").ok()?.components();
+                comps.next()?.as_os_str().to_str()?.parse().ok()
+            }
+            NSSource::Unavail(_) => None,
+        }
+    }
+    /// Checks that the anchoring process is the one recorded, not a later one of the same PID
+    pub fn check_start(&self) -> Result<(), ValidationErr> {
+        let (Some(pid), Some(start)) = (self.anchor(), self.fp.and_then(|f| f.start)) else {
+            return Ok(());
+        };
+        match proc_start(pid) {
+            Ok(now) if now == start => Ok(()),
+            Ok(_) => Err(ValidationErr::Reused),
+            Err(_) => Err(ValidationErr::ProcessGone),
+        }
+    }
+    /// The NS of the kind that the anchoring process is in now
+    pub fn proc_unique(&self, kind: &str) -> Option<UniqueFile> {
+        let pa = format!("/proc/{}/ns/{}", self.anchor()?, kind);
+        Some(nix::sys::stat::stat(pa.as_str()).ok()?.into())
+    }
+}
+
+/// Start time of a process, field 22 of its stat
+pub fn proc_start(pid: pid_t) -> Result<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command may contain spaces and parentheses
+    let rest = stat
+        .rsplit_once(')')
+        .ok_or(anyhow::anyhow!("malformed stat of {}", pid))?
+        .1;
+    let start = rest
+        .split_whitespace()
+        .nth(19)
+        .ok_or(anyhow::anyhow!("malformed stat of {}", pid))?;
+    Ok(start.parse()?)
+}
+
+/// Cookie of the current NetNS
+pub fn netns_cookie() -> Result<u64> {
+    use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+    let sock = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let mut cookie: u64 = 0;
+    let mut len = std::mem::size_of::<u64>() as libc::socklen_t;
+    let rx = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_NETNS_COOKIE,
+            &mut cookie as *mut u64 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    Errno::result(rx)?;
+    Ok(cookie)
+}
+
+/// Cookie of the NetNS of the file, read from a thread that enters it
+pub fn netns_cookie_of(ns: std::os::fd::OwnedFd) -> Result<u64> {
+    std::thread::spawn(move || {
+        nix::sched::setns(ns, nix::sched::CloneFlags::CLONE_NEWNET)?;
+        netns_cookie()
+    })
+    .join()
+    .map_err(|_| anyhow::anyhow!("thread panicked"))?
+}
+
 impl UniqueFile {
     pub fn validate(&self, fst: &stat) -> Result<(), ValidationErr> {
         if fst.st_ino == self.ino && fst.st_dev == self.dev {
@@ -202,6 +296,8 @@ pub enum ValidationErr {
     FileNonExistProc,
     ProcessGone,
     Permission,
+    /// The PID or the NS was reused by something else
+    Reused,
 }
 
 #[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -337,6 +433,23 @@ pub fn cached_stat<'k>(ca: &'k mut VaCache, path: NMnt) -> Result<&'k stat> {
     })
 }
 
+#[test]
+fn start_self() -> Result<()> {
+    let pid = getpid().as_raw();
+    assert_eq!(proc_start(pid)?, proc_start(pid)?);
+    let ns = ExactNS {
+        unique: UniqueFile::new(0, 0),
+        source: NSSource::Path(format!("/proc/{}/ns/net", pid).into()),
+        fp: Some(Fingerprint {
+            start: Some(proc_start(pid)? + 1),
+            cookie: None,
+        }),
+    };
+    assert_eq!(ns.anchor(), Some(pid));
+    assert!(matches!(ns.check_start(), Err(ValidationErr::Reused)));
+    Ok(())
+}
+
 #[test]
 fn test_f() {
     let rx = nix::sys::stat::stat("./nonexist");
@@ -31,7 +31,8 @@ fn main() {
         "{}",
         ExactNS {
             unique: UniqueFile { dev: 2, ino: 3 },
-            source: nsproxy_common::NSSource::Pid(3)
+            source: nsproxy_common::NSSource::Pid(3),
+            fp: None,
         }
     )
 }
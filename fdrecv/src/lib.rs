@@ -0,0 +1,99 @@
+//! Receive the FDs nsproxy creates in a node.
+//!
+//! The handoff protocol is a Unix stream socket, the listener, owned by the receiver.
+//! The probe of the node connects to it, sends one FD as `SCM_RIGHTS` ancillary data, and closes the connection.
+//! Nothing else is written. The probe runs again whenever the node restarts, so keep accepting.
+//!
+//! The FD is one of
+//! - a TUN device, with packet information, already up and routed as the default route of the node
+//! - a TCP socket bound in the node, already listening
+//!
+//! The listener is either a path the receiver binds, given to `--tun-to-socket` or `--listener`,
+//! or a socket unit nsproxy writes for `--tun-to-unit` or `--unit`, passed to the service by systemd.
+//!
+//! ```no_run
+//! let listener = fdrecv::from_systemd()?;
+//! loop {
+//!     let tun = fdrecv::accept(&listener)?;
+//!     // Hand the TUN to the proxy
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    env::var,
+    io::{Error, ErrorKind, Result},
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use passfd::FdPassingExt;
+
+/// The first FD systemd passes
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listener, as passed by systemd socket activation
+pub fn from_systemd() -> Result<UnixListener> {
+    let pid: u32 = var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .ok_or(Error::new(ErrorKind::NotFound, "LISTEN_PID is not set"))?;
+    if pid != std::process::id() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "LISTEN_PID is for another process",
+        ));
+    }
+    let fds: u32 = var("LISTEN_FDS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(0);
+    if fds < 1 {
+        return Err(Error::new(ErrorKind::NotFound, "No FD was passed"));
+    }
+    Ok(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Binds the listener at the path, replacing a stale socket file
+pub fn listen(path: impl AsRef<Path>) -> Result<UnixListener> {
+    let path = path.as_ref();
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Waits for the probe, and takes the FD it sends
+pub fn accept(listener: &UnixListener) -> Result<OwnedFd> {
+    let (conn, _addr) = listener.accept()?;
+    recv(&conn)
+}
+
+/// Takes the FD from an accepted connection
+pub fn recv(conn: &UnixStream) -> Result<OwnedFd> {
+    let fd = conn.recv_fd()?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[test]
+fn handoff() -> Result<()> {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        os::fd::AsRawFd,
+    };
+
+    let (sa, sb) = UnixStream::pair()?;
+    let (mut pr, pw) = UnixStream::pair()?;
+    sa.send_fd(pw.as_raw_fd())?;
+    let mut got = File::from(recv(&sb)?);
+    got.write_all(b"fd")?;
+    drop((got, pw));
+    let mut buf = Vec::new();
+    pr.read_to_end(&mut buf)?;
+    assert_eq!(buf, b"fd");
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! Append-only log of effects, one JSON object per line, at `audit.jsonl` in the state dir.
+//! Entries are the [Action]s of [plan](crate::plan), appended once they succeed.
+
+use std::{
+    fs::{metadata, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::unix::fs::{chown, MetadataExt},
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::*;
+use crate::{
+    data::{NodeAddr, NodeI},
+    paths::PathState,
+    plan::Action,
+};
+
+static AUDIT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[public]
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    /// Unix time
+    time: u64,
+    pid: u32,
+    #[serde(flatten)]
+    action: Action,
+}
+
+pub fn path(paths: &PathState) -> PathBuf {
+    paths.state.join("audit.jsonl")
+}
+
+/// Actions recorded after this go to the log of the state dir
+pub fn open(paths: &PathState) {
+    *AUDIT.lock().unwrap() = Some(path(paths));
+}
+
+/// Failures are logged, but never fail the action
+pub fn record(action: &Action) {
+    // Saves are frequent, and tell nothing by themselves
+    if matches!(action, Action::WriteState(_)) {
+        return;
+    }
+    let pa = match AUDIT.lock().unwrap().clone() {
+        Some(pa) => pa,
+        None => return,
+    };
+    if let Err(e) = append(&pa, action) {
+        warn!("Failed to write audit log {:?}, {}", pa, e);
+    }
+}
+
+/// Borrowing counterpart of [Entry]
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    time: u64,
+    pid: u32,
+    #[serde(flatten)]
+    action: &'a Action,
+}
+
+fn append(pa: &PathBuf, action: &Action) -> Result<()> {
+    let mut line = serde_json::to_string(&EntryRef {
+        time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        pid: std::process::id(),
+        action,
+    })?;
+    line.push('\n');
+    let created = !pa.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(pa)?;
+    if created {
+        // The state dir belongs to the user the nodes belong to
+        if let Some(dir) = pa.parent() {
+            chown(pa, Some(metadata(dir)?.uid()), None)?;
+        }
+    }
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Entries in order. Lines that can not be parsed are skipped.
+pub fn read(paths: &PathState) -> Result<Vec<Entry>> {
+    let pa = path(paths);
+    if !pa.exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(std::fs::File::open(&pa)?)
+        .lines()
+        .enumerate()
+    {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(en) => entries.push(en),
+            Err(e) => warn!("Skipped line {} of {:?}, {}", n + 1, pa, e),
+        }
+    }
+    Ok(entries)
+}
+
+/// Formats unix time as UTC, ex. 2024-01-31 08:00:00
+fn utc(secs: u64) -> String {
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // Days to civil date, from Howard Hinnant's algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} [{}] {}",
+            utc(self.time),
+            self.pid,
+            self.action
+        ))
+    }
+}
+
+impl Action {
+    /// Nodes the action concerns
+    fn nodes(&self) -> Vec<NodeI> {
+        match self {
+            Self::AddNode { ix, .. } | Self::RemoveNode { ix, .. } => vec![*ix],
+            Self::AddEdge { src, dst, .. } | Self::RemoveEdge { src, dst, .. } => vec![*src, *dst],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Entries concerning the node. Indices are reused, so a name or NetNS
+/// matches an index only from when it was added as such, until it was removed.
+pub fn query<'e>(entries: &'e [Entry], node: &NodeAddr) -> Vec<&'e Entry> {
+    let mut live: Option<NodeI> = None;
+    let mut found = Vec::new();
+    for en in entries {
+        let current = match node {
+            NodeAddr::Ix(ix) => Some(*ix),
+            _ => {
+                if let Action::AddNode { ix, name, net, .. } = &en.action {
+                    let matched = match node {
+                        NodeAddr::Name(n) => name.as_ref() == Some(n),
+                        NodeAddr::UF(uf) => net == uf,
+                        NodeAddr::Ix(_) => unreachable!(),
+                    };
+                    if matched {
+                        live = Some(*ix);
+                    } else if live == Some(*ix) {
+                        live = None;
+                    }
+                }
+                live
+            }
+        };
+        if let Some(ix) = current {
+            if en.action.nodes().contains(&ix) {
+                found.push(en);
+            }
+        }
+        if let Action::RemoveNode { ix, .. } = &en.action {
+            if live == Some(*ix) {
+                live = None;
+            }
+        }
+    }
+    found
+}
+
+#[test]
+fn query_reused_index() -> Result<()> {
+    use nsproxy_common::UniqueFile;
+
+    let add = |name: &str, ino| Entry {
+        time: 0,
+        pid: 1,
+        action: Action::AddNode {
+            ix: NodeI::new(3),
+            name: Some(name.to_owned()),
+            net: UniqueFile::new(ino, 4),
+            found: false,
+        },
+    };
+    let rm = Entry {
+        time: 0,
+        pid: 1,
+        action: Action::RemoveNode {
+            ix: NodeI::new(3),
+            name: Some("browser".to_owned()),
+            reasons: vec!["FileNonExist".to_owned()],
+        },
+    };
+    let lines: Vec<String> = [add("browser", 10), rm, add("chat", 11)]
+        .iter()
+        .map(serde_json::to_string)
+        .try_collect()?;
+    let entries: Vec<Entry> = lines
+        .iter()
+        .map(|l| serde_json::from_str(l))
+        .try_collect()?;
+    assert_eq!(
+        query(&entries, &NodeAddr::Name("browser".to_owned())).len(),
+        2
+    );
+    assert_eq!(query(&entries, &NodeAddr::Name("chat".to_owned())).len(), 1);
+    assert_eq!(query(&entries, &NodeAddr::Ix(NodeI::new(3))).len(), 3);
+    assert_eq!(utc(1709164800), "2024-02-29 00:00:00");
+    Ok(())
+}
@@ -16,10 +16,15 @@ use std::{
 use anyhow::{anyhow, bail};
 use log::info;
 use nix::unistd::execve;
-use nsproxy::{aok, UID_HINT_VAR};
+use nsproxy::{aok, GIT_SHA, UID_HINT_VAR};
 use tracing::warn;
 
 fn main() -> anyhow::Result<()> {
+    // For nsproxy doctor, to check that both binaries are of the same version
+    if args().nth(1).as_deref() == Some("--sha") {
+        println!("{}", GIT_SHA);
+        return Ok(());
+    }
     let mut ce = std::env::current_exe()?;
     ce.set_file_name("nsproxy");
     info!("exec {:?}", &ce);
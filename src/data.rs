@@ -1,6 +1,6 @@
 use std::{
     borrow::Cow,
-    collections::{hash_map, HashMap, HashSet},
+    collections::{hash_map, HashMap, HashSet, VecDeque},
     default,
     fmt::{Display, Write},
     net::SocketAddr,
@@ -10,26 +10,40 @@ use std::{
 };
 
 use crate::{
+    identity::Identity,
+    keeper::HeldNS,
     managed::{ItemRM, NodeWDeps},
-    paths::PathState,
-    sys::NSEnter,
+    paths::{Binds, PathState},
+    plan::{Action, Planned, Recorder},
+    sys::{enter_as_owner, NSEnter},
+    topology::NodeSpec,
 };
 
 use super::*;
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
 use bimap::BiMap;
 use clap::{Parser, ValueEnum};
 use derivative::Derivative;
 
 use linux_raw_sys::ioctl::NS_GET_USERNS;
 use netlink_ops::{errors::ProgrammingError, netlink::VethConn};
-use nix::sched::{setns, CloneFlags};
+use nix::{
+    errno::Errno,
+    sched::{setns, CloneFlags},
+};
 use nsproxy_derive::Validate;
 
-use daggy::{petgraph::stable_graph::StableDiGraph, Dag, EdgeIndex, NodeIndex};
+use daggy::{
+    petgraph::{
+        stable_graph::StableDiGraph,
+        visit::{EdgeRef, IntoEdgeReferences},
+        Direction,
+    },
+    Dag, EdgeIndex, NodeIndex,
+};
 use owo_colors::OwoColorize;
 use serde::{de::Visitor, Deserialize, Serialize};
-use tracing::info;
+use tracing::{info, warn};
 use tun::Layer;
 
 pub use nsproxy_common::*;
@@ -43,6 +57,10 @@ struct ObjectNode {
     /// It means all systemd services of this node belong to root
     /// which is separated from the user services
     root: bool,
+    /// Hostname and machine-id given to the node
+    identity: Option<Identity>,
+    /// Spec that apply created the node from. apply removes only these nodes
+    applied: Option<NodeSpec>,
 }
 
 #[public]
@@ -169,6 +187,18 @@ pub enum Route {
     DefaultRoute,
     /// A socket is created in A, and sent to B, which receives traffic from A at a port.
     ListenedBy,
+    /// A veth connects A to B, which A routes through.
+    Link,
+}
+
+impl From<&Relation> for Route {
+    fn from(value: &Relation) -> Self {
+        match value {
+            Relation::SendTUN(_) => Route::DefaultRoute,
+            Relation::SendSocket(_) => Route::ListenedBy,
+            Relation::Veth(_) => Route::Link,
+        }
+    }
 }
 
 pub type Ix = u32;
@@ -187,12 +217,19 @@ struct NSGroup<N = ExactNS> {
     net: NSSlot<N, NSNet>,
     user: NSSlot<N, NSUser>,
     pid: NSSlot<N, NSPid>,
+    /// Nodes share these with the host unless they have their own.
+    /// Absent in that case, so entering a node does not touch them.
+    ipc: NSSlot<N, NSIpc>,
+    cgroup: NSSlot<N, NSCgroup>,
+    time: NSSlot<N, NSTime>,
 }
 
 #[public]
 struct NSState<'n> {
     target: &'n NSGroup<ExactNS>,
     va: &'n mut VaCache,
+    /// FDs from the keeper of the node, entered in place of the sources
+    held: Option<HeldNS>,
 }
 
 impl<'n> NSState<'n> {
@@ -204,15 +241,21 @@ impl<'n> NSState<'n> {
             // Just error in this case, which should prevent a large number of mistakes.
         }
         let mut val = NSGroup::<[Option<ValidateR>; 2]>::default();
-        let ctx = if ctx.pid.must()?.unique == self.target.pid.must()?.unique {
+        // Absent when the node shares the PID NS, with its creator
+        let same_pid = self.target.pid.absent()
+            || self
+                .target
+                .pid
+                .map(|p| ctx.pid.map(|c| p.unique == c.unique));
+        let ctx = if same_pid {
             if ctx.mnt.must()?.unique == self.target.mnt.must()?.unique {
                 val.validate_all(cache, &ctx, self.target, 0)?;
-                self.target.enter(&ctx)?;
+                self.target.enter_held(&ctx, self.held.as_ref())?;
                 let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
                 ctx
             } else {
                 val.validate_all(cache, &ctx, self.target, 0)?;
-                self.target.enter(&ctx)?;
+                self.target.enter_held(&ctx, self.held.as_ref())?;
                 let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
                 val.validate_all(cache, &ctx, self.target, 1)?;
                 ctx
@@ -220,13 +263,13 @@ impl<'n> NSState<'n> {
         } else {
             if ctx.mnt.must()?.unique == self.target.mnt.must()?.unique {
                 val.validate_all(cache, &ctx, self.target, 0)?;
-                self.target.enter(&ctx)?;
+                self.target.enter_held(&ctx, self.held.as_ref())?;
                 let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
                 val.validate_all(cache, &ctx, self.target, 1)?;
                 ctx
             } else {
                 val.validate_all(cache, &ctx, self.target, 0)?;
-                self.target.enter(&ctx)?;
+                self.target.enter_held(&ctx, self.held.as_ref())?;
                 let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
                 val.validate_all(cache, &ctx, self.target, 1)?;
                 ctx
@@ -234,6 +277,12 @@ impl<'n> NSState<'n> {
         };
         log::info!("{}", &val);
         assert_eq!(self.target.net.must()?.unique, ctx.net.must()?.unique);
+        if let Some(cookie) = self.target.net.must()?.fp.and_then(|f| f.cookie) {
+            // Cookies are unavailable before Linux 5.14
+            if netns_cookie().is_ok_and(|now| now != cookie) {
+                Err(ValidationErr::Reused).context("NetNS was reused")?;
+            }
+        }
         assert_eq!(self.target.uts.must()?.unique, ctx.uts.must()?.unique);
         Ok(())
     }
@@ -269,7 +318,9 @@ impl<const L: usize> NSGroup<[Option<ValidateR>; L]> {
                         }
                     }
                     NSSource::Pid(_) => {
-                        if target.pid.map(|m| ctx.pid.map(|c| m.unique == c.unique)) {
+                        if target.pid.absent()
+                            || target.pid.map(|m| ctx.pid.map(|c| m.unique == c.unique))
+                        {
                             ns.validate(cache, ctx)?
                         } else {
                             ValidateR::Impossible
@@ -293,6 +344,9 @@ impl<const L: usize> NSGroup<[Option<ValidateR>; L]> {
         self.validate::<NSNet>(cache, ctx, target, pos)?;
         self.validate::<NSPid>(cache, ctx, target, pos)?;
         self.validate::<NSUts>(cache, ctx, target, pos)?;
+        self.validate::<NSIpc>(cache, ctx, target, pos)?;
+        self.validate::<NSCgroup>(cache, ctx, target, pos)?;
+        self.validate::<NSTime>(cache, ctx, target, pos)?;
         Ok(())
     }
 }
@@ -300,6 +354,7 @@ impl<const L: usize> NSGroup<[Option<ValidateR>; L]> {
 impl Display for NSGroup<ExactNS> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         wns!(f, self, user, mnt, net, uts, pid);
+        wns_opt!(f, self, ipc, cgroup, time);
         Ok(())
     }
 }
@@ -307,7 +362,7 @@ impl Display for NSGroup<ExactNS> {
 impl<const NUM: usize> Display for NSGroup<[Option<ValidateR>; NUM]> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("Validation Result \n"))?;
-        wns_val!(f, self, user, mnt, net, uts, pid);
+        wns_val!(f, self, user, mnt, net, uts, pid, ipc, cgroup, time);
         Ok(())
     }
 }
@@ -316,6 +371,10 @@ macro wns($f:ident, $s:ident, $($fi:ident),*) {
     $( $f.write_fmt(format_args!("      {} \n", $s.$fi))?;)*
 }
 
+macro wns_opt($f:ident, $s:ident, $($fi:ident),*) {
+    $( if !$s.$fi.absent() { $f.write_fmt(format_args!("      {} \n", $s.$fi))?; })*
+}
+
 macro wns_val($f:ident, $s:ident, $($fi:ident),*) {
     $( $f.write_fmt(format_args!("      {:?} \n", $s.$fi))?;)*
 }
@@ -326,22 +385,49 @@ pub macro mount_by_pid( $pid:expr,$binds:expr,$group:ident,$v:expr,[$($name:iden
     )*
 }
 
+/// The binds are recorded instead, when planning
 pub fn mount_ns_by_pid(
     pid: PidPath,
     paths: &PathState,
     id: NodeI,
-    do_mount: bool,
+    plan: Option<&Recorder>,
     root: bool,
 ) -> Result<NSGroup<ExactNS>> {
-    let binds = paths.mount(id, root)?;
+    let binds = if plan.is_some() {
+        Binds(paths.mount_path(id, root)?)
+    } else {
+        paths.mount(id, root)?
+    };
     let mut nsg: NSGroup<ExactNS> = NSGroup::default();
-    mount_by_pid!(pid, &binds, nsg, do_mount, [net, uts, pid]);
+    mount_by_pid!(pid, &binds, nsg, plan, [net, uts]);
+    let ours = NSGroup::proc_path(PidPath::Selfproc, None)?;
+    let mut own = NSGroup::proc_path(pid, None)?;
+    own.drop_shared(&ours);
+    // Nodes with a machine-id of their own
+    if own.mnt.map(|a| ours.mnt.map(|b| a.unique != b.unique)) {
+        mount_by_pid!(pid, &binds, nsg, plan, [mnt]);
+    }
+    if !own.pid.absent() {
+        mount_by_pid!(pid, &binds, nsg, plan, [pid]);
+    }
+    if !own.ipc.absent() {
+        mount_by_pid!(pid, &binds, nsg, plan, [ipc]);
+    }
+    if !own.cgroup.absent() {
+        mount_by_pid!(pid, &binds, nsg, plan, [cgroup]);
+    }
+    if !own.time.absent() {
+        mount_by_pid!(pid, &binds, nsg, plan, [time]);
+    }
     Ok(nsg)
 }
 
 #[public]
 impl NSGroup<ExactNS> {
     fn enter(&self, ctx: &NSGroup<ExactNS>) -> Result<()> {
+        self.enter_held(ctx, None)
+    }
+    fn enter_held(&self, ctx: &NSGroup<ExactNS>, held: Option<&HeldNS>) -> Result<()> {
         match &self.user {
             NSSlot::Provided(ns, _) => {
                 if let NSSource::Unavail(b) = ns.source {
@@ -351,20 +437,79 @@ impl NSGroup<ExactNS> {
                         setns(&usr, CloneFlags::CLONE_NEWUSER)?;
                     }
                 } else {
-                    self.user.enter_if(ctx)?;
+                    if !self.user.entered(ctx)? {
+                        enter_as_owner(ns)?;
+                    }
+                    self.user.enter_via(ctx, held)?;
                 }
             }
             _ => (),
         }
 
-        ns_call!(self, [mnt, net, uts], enter_if, ctx);
+        // Entering the PID NS takes effect on the processes we spawn
+        ns_call!(
+            self,
+            [mnt, net, uts, ipc, cgroup, time, pid],
+            enter_via,
+            ctx,
+            held
+        );
         Ok(())
     }
     fn proc_path(pid: PidPath, alt: Option<NSSource>) -> Result<Self> {
         let mut g = Self::default();
         assign!(g, [user, mnt, net, pid, uts], proc_path, pid, alt.clone());
+        // Older kernels lack some
+        assign!(g, [ipc, cgroup, time], proc_path_opt, pid, alt.clone());
         Ok(g)
     }
+    /// Points the NSes anchored at the exited process old to new, where new is in the same NSes
+    fn rehome(&mut self, old: pid_t, new: pid_t) -> Result<()> {
+        ns_call!(
+            self,
+            [user, mnt, net, uts, pid, ipc, cgroup, time],
+            rehome,
+            old,
+            new
+        );
+        Ok(())
+    }
+    /// Forgets the PID, IPC, cgroup and time NSes that are the same as in ctx
+    fn drop_shared(&mut self, ctx: &NSGroup<ExactNS>) {
+        // Sources differ between processes, so only the inodes are compared
+        if self.pid.map(|a| ctx.pid.map(|b| a.unique == b.unique)) {
+            self.pid = NSSlot::Absent;
+        }
+        if self.ipc.map(|a| ctx.ipc.map(|b| a.unique == b.unique)) {
+            self.ipc = NSSlot::Absent;
+        }
+        if self
+            .cgroup
+            .map(|a| ctx.cgroup.map(|b| a.unique == b.unique))
+        {
+            self.cgroup = NSSlot::Absent;
+        }
+        if self.time.map(|a| ctx.time.map(|b| a.unique == b.unique)) {
+            self.time = NSSlot::Absent;
+        }
+    }
+    /// Records what tells the NSes from later ones that reuse the inodes, or the PID
+    fn fingerprint(&mut self, pid: PidPath) {
+        let start = match pid {
+            PidPath::N(n) => proc_start(n).ok(),
+            // We exit soon
+            PidPath::Selfproc => None,
+        };
+        let fp = Fingerprint {
+            start,
+            cookie: None,
+        };
+        set_fp!(self, fp, [user, mnt, uts, pid, ipc, cgroup, time]);
+        let cookie = std::fs::File::open(format!("/proc/{}/ns/net", pid.to_str()))
+            .ok()
+            .and_then(|f| netns_cookie_of(f.into()).ok());
+        set_fp!(self, Fingerprint { start, cookie }, [net]);
+    }
     fn key(&self) -> UniqueFile {
         match &self.net {
             NSSlot::Provided(a, _) => a.unique,
@@ -373,9 +518,24 @@ impl NSGroup<ExactNS> {
     }
 }
 
-pub macro ns_call( $group:ident, [$($name:ident),*],  $func:ident, $arg:expr) {
+pub macro ns_call {
+    ( $group:ident, [$($name:ident),*],  $func:ident, $arg:expr) => {
+        $(
+            $group.$name.$func($arg)?;
+        )*
+    },
+    ( $group:ident, [$($name:ident),*],  $func:ident, $arg:expr, $arg1:expr) => {
+        $(
+            $group.$name.$func($arg, $arg1)?;
+        )*
+    }
+}
+
+macro set_fp($group:ident, $fp:expr, [$($name:ident),*]) {
     $(
-        $group.$name.$func($arg)?;
+        if let NSSlot::Provided(ns, _) = &mut $group.$name {
+            ns.fp = Some($fp);
+        }
     )*
 }
 
@@ -386,12 +546,13 @@ pub macro assign( $group:ident, [$($name:ident),*],  $func:ident, $arg:expr, $ar
 }
 
 impl AddAssign<&NSGroup<ExactNS>> for NSGroup<ExactNS> {
-    /// Assigns with the user&mnt of rhs
+    /// Assigns with the user&mnt of rhs, but keeps a mnt NS the node has of its own
     fn add_assign(&mut self, rhs: &Self) {
         if !rhs.user.absent() {
             self.user = rhs.user.clone();
         }
-        if !rhs.mnt.absent() {
+        let own_mnt = self.mnt.map(|a| rhs.mnt.map(|b| a.unique != b.unique));
+        if !rhs.mnt.absent() && !own_mnt {
             self.mnt = rhs.mnt.clone();
         }
     }
@@ -455,10 +616,12 @@ impl Validate for ExactNS {
             NSSource::Path(p) => {
                 let st = cached_stat(cache, (p, &ctx.mnt.must()?.unique))?;
                 self.unique.validate(st)?;
+                self.check_start()?;
             }
             NSSource::Pid(p) => {
                 let st = cached_fstat(cache, (*p, &ctx.pid.must()?.unique))?;
                 self.unique.validate(st)?;
+                self.check_start()?;
             }
             NSSource::Unavail(_) => return Ok(ValidateR::Unspec),
         }
@@ -477,14 +640,35 @@ impl<K: NSTrait + PartialEq> NSSlot<ExactNS, K> {
     }
     /// Enter the NS if provided
     fn enter_if(&self, ctx: &NSGroup<ExactNS>) -> Result<()> {
+        self.enter_via(ctx, None)
+    }
+    /// Whether ctx is in the NS already
+    fn entered(&self, ctx: &NSGroup<ExactNS>) -> Result<bool> {
+        match self {
+            Self::Absent => Ok(true),
+            Self::Provided(ns, _) => {
+                let curr = K::get(ctx).must()?.unique;
+                // A PID source is recorded by its pidfd, so the NS is looked up
+                let pid_in =
+                    matches!(ns.source, NSSource::Pid(_)) && ns.proc_unique(K::NAME) == Some(curr);
+                Ok(curr == ns.unique || pid_in)
+            }
+        }
+    }
+    /// Enter the NS if provided, through the FD held by the keeper if there is one
+    fn enter_via(&self, ctx: &NSGroup<ExactNS>, held: Option<&HeldNS>) -> Result<()> {
         match self {
             Self::Absent => Ok(()),
             Self::Provided(ns, _) => {
-                if K::get(ctx).must()?.unique == ns.unique {
+                if self.entered(ctx)? {
                     Ok(())
                 } else {
                     if matches!(ns.source, NSSource::Unavail(_)) {
                         Ok(())
+                    } else if let Some(fd) = held.and_then(|h| h.get(K::NAME, ns)) {
+                        log::info!("Enter {:?} by the keeper, {}", K::NAME, ns);
+                        setns(fd, K::FLAG)?;
+                        Ok(())
                     } else {
                         log::info!("Enter {:?}, {}", K::NAME, ns);
                         ns.enter(K::FLAG)
@@ -493,6 +677,24 @@ impl<K: NSTrait + PartialEq> NSSlot<ExactNS, K> {
             }
         }
     }
+    fn rehome(&mut self, old: pid_t, new: pid_t) -> Result<()> {
+        if let Self::Provided(ns, _) = self {
+            if matches!(ns.source, NSSource::Path(_)) && ns.anchor() == Some(old) {
+                let pa = PathBuf::from(format!("/proc/{}/ns/{}", new, K::NAME));
+                match nix::sys::stat::stat(&pa) {
+                    Ok(st) if ns.unique.validate(&st).is_ok() => {
+                        ns.source = NSSource::Path(pa);
+                        // The start time was of the old process
+                        if let Some(fp) = &mut ns.fp {
+                            fp.start = proc_start(new).ok();
+                        }
+                    }
+                    _ => warn!("{} NS {} is not held by {}", K::NAME, ns.unique, new),
+                }
+            }
+        }
+        Ok(())
+    }
     fn must(&self) -> Result<&ExactNS> {
         match &self {
             Self::Absent => bail!(Unviable),
@@ -514,10 +716,17 @@ impl<K: NSTrait + PartialEq> NSSlot<ExactNS, K> {
             ExactNS {
                 source: altsource.unwrap_or(NSSource::Path(path)),
                 unique: stat.into(),
+                fp: None,
             },
             Default::default(),
         ))
     }
+    fn proc_path_opt(pid: PidPath, altsource: Option<NSSource>) -> Result<Self> {
+        match Self::proc_path(pid, altsource) {
+            Err(e) if e.downcast_ref::<Errno>() == Some(&Errno::ENOENT) => Ok(NSSlot::Absent),
+            rx => rx,
+        }
+    }
     fn user_ns(&self) -> Result<std::fs::File> {
         match &self {
             Self::Absent => bail!(Unviable),
@@ -540,6 +749,7 @@ impl<K: NSTrait + PartialEq> NSSlot<ExactNS, K> {
             ExactNS {
                 source: NSSource::Unavail(true),
                 unique: stat.into(),
+                fp: None,
             },
             Default::default(),
         ))
@@ -551,11 +761,17 @@ impl<K: NSTrait + PartialEq> NSSlot<ExactNS, K> {
 #[error("{:?}", self)]
 struct Unviable;
 
-defNS!(NSUser, CLONE_NEWUSER, "user", user);
-defNS!(NSMnt, CLONE_NEWNS, "mnt", mnt);
-defNS!(NSNet, CLONE_NEWNET, "net", net);
-defNS!(NSUts, CLONE_NEWUTS, "uts", uts);
-defNS!(NSPid, CLONE_NEWPID, "pid", pid);
+defNS!(NSUser, CloneFlags::CLONE_NEWUSER, "user", user);
+defNS!(NSMnt, CloneFlags::CLONE_NEWNS, "mnt", mnt);
+defNS!(NSNet, CloneFlags::CLONE_NEWNET, "net", net);
+defNS!(NSUts, CloneFlags::CLONE_NEWUTS, "uts", uts);
+defNS!(NSPid, CloneFlags::CLONE_NEWPID, "pid", pid);
+defNS!(NSIpc, CloneFlags::CLONE_NEWIPC, "ipc", ipc);
+defNS!(NSCgroup, CloneFlags::CLONE_NEWCGROUP, "cgroup", cgroup);
+defNS!(NSTime, CLONE_NEWTIME, "time", time);
+
+/// Absent from nix
+const CLONE_NEWTIME: CloneFlags = CloneFlags::from_bits_retain(libc::CLONE_NEWTIME);
 
 pub fn nstypes() -> HashMap<&'static str, fn(&mut NSGroup<ExactNS>, ExactNS)> {
     let mut map = HashMap::new();
@@ -575,14 +791,30 @@ pub fn nstypes() -> HashMap<&'static str, fn(&mut NSGroup<ExactNS>, ExactNS)> {
         <NSUts as NSTrait>::NAME,
         NSUts::set as for<'a> fn(&'a mut data::NSGroup<ExactNS>, _),
     );
+    map.insert(
+        <NSPid as NSTrait>::NAME,
+        NSPid::set as for<'a> fn(&'a mut data::NSGroup<ExactNS>, _),
+    );
+    map.insert(
+        <NSIpc as NSTrait>::NAME,
+        NSIpc::set as for<'a> fn(&'a mut data::NSGroup<ExactNS>, _),
+    );
+    map.insert(
+        <NSCgroup as NSTrait>::NAME,
+        NSCgroup::set as for<'a> fn(&'a mut data::NSGroup<ExactNS>, _),
+    );
+    map.insert(
+        <NSTime as NSTrait>::NAME,
+        NSTime::set as for<'a> fn(&'a mut data::NSGroup<ExactNS>, _),
+    );
     map
 }
 
-pub macro defNS($name:ident, $flag:ident, $path:expr, $k:ident) {
+pub macro defNS($name:ident, $flag:expr, $path:expr, $k:ident) {
     #[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
     pub struct $name;
     impl NSTrait for $name {
-        const FLAG: CloneFlags = CloneFlags::$flag;
+        const FLAG: CloneFlags = $flag;
         const NAME: &'static str = $path;
         fn set<N>(g: &mut NSGroup<N>, v: N) {
             g.$k = NSSlot::Provided(v, Self);
@@ -628,8 +860,18 @@ struct Graphs {
     /// For simplicity, for one netns, only one object may exist, and other NSes are attached to it.
     map: HashMap<UniqueFile, NodeI>,
     name: BiMap<String, NodeI>,
+    /// Held while the graphs are loaded
     #[serde(skip)]
-    file: Option<std::fs::File>,
+    lock: Option<crate::lock::StateLock>,
+    /// Set when planning
+    #[serde(skip)]
+    plan: Option<Recorder>,
+}
+
+impl Planned for Graphs {
+    fn recorder(&self) -> Option<&Recorder> {
+        self.plan.recorder()
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -674,8 +916,8 @@ impl Graphs {
             let mut node = match method {
                 NSAdd::RecordMountedPaths => {
                     // Always try unmount
-                    NSGroup::umount(ix, paths, rootful)?;
-                    mount_ns_by_pid(pid, paths, ix, true, rootful)?
+                    NSGroup::umount(ix, paths, rootful, self.plan.as_ref())?;
+                    mount_ns_by_pid(pid, paths, ix, self.plan.as_ref(), rootful)?
                 }
                 NSAdd::RecordProcfsPaths => NSGroup::proc_path(pid.to_n(), None)?,
                 NSAdd::RecordNothing => NSGroup::proc_path(pid, Some(NSSource::Unavail(false)))?,
@@ -688,6 +930,9 @@ impl Graphs {
                     g
                 }
             };
+            // Entering a node leaves these as they are, unless it has its own
+            node.drop_shared(&NSGroup::proc_path(PidPath::Selfproc, None)?);
+            node.fingerprint(pid);
             if let Some(p) = usermnt {
                 node += p;
             }
@@ -698,24 +943,98 @@ impl Graphs {
                 name,
                 main: node,
                 root: rootful,
+                identity: None,
+                applied: None,
             })
         };
         match self.map.entry(uf) {
             hash_map::Entry::Occupied(en) => {
                 let ns = *en.get();
                 log::info!("NS object {pid:?} {:?} exists", ns);
+                let act = Action::AddNode {
+                    ix: ns,
+                    name: name.clone(),
+                    net: uf,
+                    found: true,
+                };
+                self.plan.record(&act);
                 self.data[ns].replace(makenode(ns)?);
+                self.plan.done(&act);
                 log::info!("Updated NS node");
                 Ok((NSAddRes::Found, ns))
             }
             hash_map::Entry::Vacant(va) => {
                 let ix: NodeI = self.data.add_node(None);
                 log::info!("New NS object {pid:?}, {:?}", ix);
+                let act = Action::AddNode {
+                    ix,
+                    name: name.clone(),
+                    net: uf,
+                    found: false,
+                };
+                self.plan.record(&act);
                 self.data[ix].replace(makenode(ix)?);
+                self.plan.done(&act);
                 Ok((NSAddRes::NewNS, *va.insert(ix)))
             }
         }
     }
+    /// Stands in for [Graphs::add_ns] when planning, as the process of the node is not forked.
+    /// Its NSes do not exist yet, so the node is keyed by a placeholder.
+    fn add_planned(
+        &mut self,
+        kinds: &[&str],
+        paths: &PathState,
+        method: NSAdd,
+        name: Option<String>,
+        rootful: bool,
+    ) -> Result<NodeI> {
+        let uf = UniqueFile::new(0, 0);
+        let ix: NodeI = self.data.add_node(None);
+        let act = Action::AddNode {
+            ix,
+            name: name.clone(),
+            net: uf,
+            found: false,
+        };
+        self.record(&act);
+        let binds = Binds(paths.mount_path(ix, rootful)?);
+        let maps = nstypes();
+        let mut node = NSGroup::default();
+        for &kind in kinds {
+            let src: PathBuf = ["/proc", "<new>", "ns", kind].iter().collect();
+            let source = if matches!(method, NSAdd::RecordMountedPaths) {
+                self.record(&Action::Mount {
+                    src,
+                    dst: binds.ns(kind),
+                });
+                NSSource::Path(binds.ns(kind))
+            } else {
+                NSSource::Path(src)
+            };
+            maps[kind](
+                &mut node,
+                ExactNS {
+                    source,
+                    unique: uf,
+                    fp: None,
+                },
+            );
+        }
+        if let Some(ref na) = name {
+            self.name.insert(na.clone(), ix);
+        }
+        self.data[ix].replace(ObjectNode {
+            name,
+            main: node,
+            root: rootful,
+            identity: None,
+            applied: None,
+        });
+        self.map.insert(uf, ix);
+        self.done(&act);
+        Ok(ix)
+    }
     fn resolve(&self, addr: &NodeAddr) -> Result<NodeI> {
         match addr {
             NodeAddr::Ix(ix) => Ok(*ix),
@@ -741,13 +1060,144 @@ impl Graphs {
         nw.remove(serv).await?;
         let mut edges_rm: Vec<_> = Default::default();
         for rel in nw.1 {
-            edges_rm.push(rel.edge.id);
+            edges_rm.push((rel.edge.id, rel.dst.id));
         }
-        for id in edges_rm {
+        for (id, dst) in edges_rm {
+            let act = Action::RemoveEdge { ix: id, src, dst };
+            self.record(&act);
             self.data.remove_edge(id);
+            self.done(&act);
         }
+        self.rebuild_route();
         Ok(())
     }
+    /// Route node of the object, created if absent
+    fn route_node(&mut self, object: NodeI) -> NodeIndex<Ix> {
+        let ix = object.index() as Ix;
+        let found = self
+            .route
+            .graph()
+            .node_indices()
+            .find(|n| self.route[*n].object == ix);
+        match found {
+            Some(n) => n,
+            None => self.route.add_node(RouteNode { object: ix }),
+        }
+    }
+    /// Mirrors an object edge into the route DAG.
+    /// Refuses edges that close a routing loop, which is reported with the path.
+    fn route_edge(&mut self, src: NodeI, dst: NodeI, route: Route) -> Result<()> {
+        let (a, b) = (self.route_node(src), self.route_node(dst));
+        if self.route.add_edge(a, b, route).is_err() {
+            let path = self.route_path(b, a).unwrap_or_else(|| vec![dst, src]);
+            let path: Vec<_> = path
+                .into_iter()
+                .chain([dst])
+                .map(|n| self.node_label(n))
+                .collect();
+            bail!(
+                "Edge {} -> {} would close a routing loop, {}",
+                self.node_label(src),
+                self.node_label(dst),
+                path.join(" -> ")
+            );
+        }
+        Ok(())
+    }
+    /// Objects on a path from a to b in the route DAG
+    fn route_path(&self, a: NodeIndex<Ix>, b: NodeIndex<Ix>) -> Option<Vec<NodeI>> {
+        let g = self.route.graph();
+        let mut prev = HashMap::new();
+        let mut seen = HashSet::from([a]);
+        let mut queue = VecDeque::from([a]);
+        while let Some(n) = queue.pop_front() {
+            if n == b {
+                let mut path = vec![n];
+                while let Some(p) = prev.get(path.last().unwrap()) {
+                    path.push(*p);
+                }
+                path.reverse();
+                return Some(path.into_iter().map(|n| g[n].object.into()).collect());
+            }
+            for next in g.neighbors_directed(n, Direction::Outgoing) {
+                if seen.insert(next) {
+                    prev.insert(next, n);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+    /// Derives the route DAG from the object graph, after edges are removed.
+    /// Edges that would close a loop are left out, with a warning.
+    fn rebuild_route(&mut self) {
+        self.route = RouteDAG::new();
+        let edges: Vec<_> = self
+            .data
+            .edge_references()
+            .filter_map(|e| {
+                e.weight()
+                    .as_ref()
+                    .map(|r| (e.source(), e.target(), Route::from(r)))
+            })
+            .collect();
+        for (src, dst, route) in edges {
+            if let Err(e) = self.route_edge(src, dst, route) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+    fn node_label(&self, ix: NodeI) -> String {
+        match self.name.get_by_right(&ix) {
+            Some(name) => format!("{} ({})", name, ix.index()),
+            None => ix.index().to_string(),
+        }
+    }
+    /// Hops from the node to the egress, preferring default routes where a node has several
+    fn chain(&self, from: NodeI) -> Vec<NodeI> {
+        let mut hops = vec![from];
+        let mut curr = from;
+        loop {
+            let next = self
+                .data
+                .edges_directed(curr, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_ref().map(|r| (e.target(), Route::from(r))))
+                .min_by_key(|(_, r)| !matches!(r, Route::DefaultRoute));
+            match next {
+                // The route DAG refuses loops, but the object graph is checked anyway
+                Some((n, _)) if !hops.contains(&n) => {
+                    hops.push(n);
+                    curr = n;
+                }
+                _ => break,
+            }
+        }
+        hops
+    }
 }
 
 // I have experimented. The inode number of root netns does not change across reboots.
+
+#[test]
+fn route_loop() -> Result<()> {
+    let mut graphs = Graphs::default();
+    let (a, b, c) = (NodeI::new(0), NodeI::new(1), NodeI::new(2));
+    graphs.name.insert("a".to_owned(), a);
+    graphs.route_edge(a, b, Route::DefaultRoute)?;
+    graphs.route_edge(b, c, Route::Link)?;
+    let err = graphs.route_edge(c, a, Route::DefaultRoute).unwrap_err();
+    assert!(err.to_string().ends_with("a (0) -> 1 -> 2 -> a (0)"));
+    assert!(graphs.route_edge(a, a, Route::Link).is_err());
+    Ok(())
+}
+
+#[test]
+fn drop_shared_by_pid() -> Result<()> {
+    let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+    let pid = nix::unistd::getpid().as_raw();
+    let mut g = NSGroup::proc_path(PidPath::N(pid), None)?;
+    g.drop_shared(&ctx);
+    assert!(g.ipc.absent() && g.cgroup.absent() && g.time.absent() && g.pid.absent());
+    assert!(!g.net.absent());
+    Ok(())
+}
@@ -0,0 +1,341 @@
+//! Checks the environment nsproxy depends on, so failures surface before a node is half-created.
+
+use std::{
+    env::current_exe,
+    fs::{metadata, read_to_string},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use nix::unistd::{access, geteuid, AccessFlags};
+use owo_colors::OwoColorize;
+
+use super::*;
+use crate::{
+    data::{FDRecver, Graphs},
+    paths::PathState,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[public]
+#[derive(Debug)]
+struct Diagnosis {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    /// How to fix it
+    hint: Option<String>,
+}
+
+impl Diagnosis {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+fn sysctl(path: &str) -> Option<String> {
+    read_to_string(path).ok().map(|s| s.trim().to_owned())
+}
+
+fn userns_sysctls(out: &mut Vec<Diagnosis>) {
+    let name = "kernel.unprivileged_userns_clone";
+    out.push(match sysctl("/proc/sys/kernel/unprivileged_userns_clone") {
+        None => Diagnosis::pass(name, "not present, unrestricted"),
+        Some(v) if v == "1" => Diagnosis::pass(name, "1"),
+        Some(v) => Diagnosis::fail(name, v, format!("sysctl -w {name}=1")),
+    });
+    let name = "user.max_user_namespaces";
+    out.push(match sysctl("/proc/sys/user/max_user_namespaces") {
+        None => Diagnosis::warn(
+            name,
+            "not readable",
+            "check that the kernel has CONFIG_USER_NS",
+        ),
+        Some(v) if v.parse::<u64>().unwrap_or(0) > 0 => Diagnosis::pass(name, v),
+        Some(v) => Diagnosis::fail(name, v, format!("sysctl -w {name}=65536")),
+    });
+    let name = "kernel.apparmor_restrict_unprivileged_userns";
+    out.push(
+        match sysctl("/proc/sys/kernel/apparmor_restrict_unprivileged_userns") {
+            None => Diagnosis::pass(name, "not present"),
+            Some(v) if v == "0" => Diagnosis::pass(name, v),
+            Some(v) => Diagnosis::warn(
+                name,
+                v,
+                format!("--userns requires sproxy. Or, sysctl -w {name}=0"),
+            ),
+        },
+    );
+}
+
+fn tun_device() -> Diagnosis {
+    let name = "/dev/net/tun";
+    match metadata(name) {
+        Ok(m) if m.file_type().is_char_device() => Diagnosis::pass(name, "character device"),
+        Ok(_) => Diagnosis::fail(name, "not a character device", "modprobe tun"),
+        Err(e) => Diagnosis::fail(name, e.to_string(), "modprobe tun"),
+    }
+}
+
+fn sproxy_bin(sproxy: &Path) -> Diagnosis {
+    let name = "sproxy";
+    let install = "sudo nsproxy install -s";
+    match metadata(sproxy) {
+        Err(e) => Diagnosis::fail(name, format!("{:?}, {}", sproxy, e), install),
+        Ok(m) if m.uid() != 0 => Diagnosis::fail(
+            name,
+            format!("{:?} is owned by uid {}", sproxy, m.uid()),
+            install,
+        ),
+        Ok(m) if m.mode() & 0o4000 == 0 => Diagnosis::fail(
+            name,
+            format!("{:?} does not have the SUID bit", sproxy),
+            install,
+        ),
+        Ok(_) => Diagnosis::pass(name, format!("{:?}, SUID root", sproxy)),
+    }
+}
+
+/// sproxy prints its SHA without asking for sudo
+fn version_match(sproxy: &Path) -> Diagnosis {
+    let name = "version";
+    let install = "sudo nsproxy install -s, to install both binaries";
+    let child = Command::new(sproxy)
+        .arg("--sha")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => return Diagnosis::fail(name, e.to_string(), install),
+    };
+    let start = Instant::now();
+    // Older versions of sproxy do not know --sha and go on to ask for sudo.
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if start.elapsed() < Duration::from_secs(3) => {
+                std::thread::sleep(Duration::from_millis(50))
+            }
+            _ => {
+                let _ = child.kill();
+                return Diagnosis::fail(name, "sproxy predates --sha", install);
+            }
+        }
+    }
+    let sha = match child.wait_with_output() {
+        Ok(o) => String::from_utf8_lossy(&o.stdout).trim().to_owned(),
+        Err(e) => return Diagnosis::fail(name, e.to_string(), install),
+    };
+    if sha == GIT_SHA {
+        Diagnosis::pass(name, GIT_SHA)
+    } else {
+        Diagnosis::fail(
+            name,
+            format!("nsproxy {}, sproxy {}", GIT_SHA, sha),
+            install,
+        )
+    }
+}
+
+/// A failure is fatal only for the bus we manage units on, which root and users differ in
+fn bus(name: &'static str, reached: Result<(), String>, required: bool, hint: &str) -> Diagnosis {
+    match reached {
+        Ok(_) => Diagnosis::pass(name, "reachable"),
+        Err(e) if required => Diagnosis::fail(name, e, hint),
+        Err(e) => Diagnosis::warn(name, e, format!("not used by this user. {}", hint)),
+    }
+}
+
+async fn buses(out: &mut Vec<Diagnosis>) {
+    let root = geteuid().is_root();
+    let reached = zbus::Connection::session()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+    out.push(bus(
+        "session bus",
+        reached,
+        !root,
+        "nodes of non-root users are managed as user units. Run in a login session with DBUS_SESSION_BUS_ADDRESS set",
+    ));
+    let reached = zbus::Connection::system()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+    out.push(bus(
+        "system bus",
+        reached,
+        root,
+        "nodes of root are managed as system units. Check that systemd is running",
+    ));
+}
+
+fn writable(out: &mut Vec<Diagnosis>, paths: &PathState, uid: u32) {
+    let mut dirs = vec![
+        ("config dir", paths.config.clone()),
+        ("state dir", paths.state.clone()),
+        ("tun2proxy dir", paths.tun2proxy()),
+    ];
+    if let Some(b) = &paths.binds {
+        dirs.push(("binds dir", b.clone()));
+    }
+    for (name, dir) in dirs {
+        out.push(match access(&dir, AccessFlags::W_OK) {
+            Ok(_) => Diagnosis::pass(name, format!("{:?}", dir)),
+            Err(e) => Diagnosis::fail(
+                name,
+                format!("{:?}, {}", dir, e),
+                format!("chown -R {} {:?}", uid, dir),
+            ),
+        });
+    }
+}
+
+/// The private binds dir must not propagate mounts, or the NS binds leak into other mount NSes
+fn propagation(paths: &PathState) -> Result<Diagnosis> {
+    let name = "binds propagation";
+    let private = match paths.private(false) {
+        Ok(p) => p,
+        Err(_) => return Ok(Diagnosis::pass(name, "not used by root")),
+    };
+    let mounts = procfs::process::Process::myself()?.mountinfo()?;
+    let found = mounts.into_iter().find(|m| m.mount_point == private);
+    Ok(match found {
+        None => Diagnosis::warn(
+            name,
+            format!("{:?} is not a mount point", private),
+            "sproxy userns, to initialize the user NS",
+        ),
+        Some(m) => {
+            if m.opt_fields
+                .iter()
+                .any(|f| matches!(f, procfs::process::MountOptFields::Shared(_)))
+            {
+                Diagnosis::fail(
+                    name,
+                    format!("{:?} is shared", private),
+                    "sproxy userns --deinit, then sproxy userns",
+                )
+            } else {
+                Diagnosis::pass(name, format!("{:?} is private", private))
+            }
+        }
+    })
+}
+
+fn ping_range(uid: u32) -> Diagnosis {
+    let name = "net.ipv4.ping_group_range";
+    let range = match sysctl("/proc/sys/net/ipv4/ping_group_range") {
+        Some(r) => r,
+        None => return Diagnosis::warn(name, "not readable", "ping may not work in nodes"),
+    };
+    let gid = uzers::get_user_by_uid(uid).map(|u| u.primary_group_id());
+    let bounds: Vec<u32> = range
+        .split_whitespace()
+        .filter_map(|k| k.parse().ok())
+        .collect();
+    match (gid, bounds.as_slice()) {
+        (Some(g), [lo, hi]) if *lo <= g && g <= *hi => Diagnosis::pass(name, range),
+        _ => Diagnosis::warn(
+            name,
+            range,
+            "nodes set it for themselves. For the root NetNS, sysctl -w net.ipv4.ping_group_range=\"0 2147483647\"",
+        ),
+    }
+}
+
+/// Each proxy must be reachable from the out node of its edge, where tun2proxy runs
+fn proxies(out: &mut Vec<Diagnosis>, paths: &PathState) {
+    let Ok(graphs) = Graphs::load_file_ro(paths) else {
+        return;
+    };
+    for edge in graphs.data.edge_indices() {
+        let Some(FDRecver::TUN2Proxy(conf)) =
+            graphs.data[edge].as_ref().and_then(|r| r.fd_recver())
+        else {
+            continue;
+        };
+        let Some((_, dst)) = graphs.data.edge_endpoints(edge) else {
+            continue;
+        };
+        let detail = format!("{:?} from node {}", conf, graphs.node_label(dst));
+        out.push(match graphs.check_proxy(conf, dst) {
+            Ok(_) => Diagnosis::pass("proxy", detail),
+            Err(e) => Diagnosis::warn(
+                "proxy",
+                format!("{}, {}", detail, e),
+                "check proxy.addr. A hop of a chain reaches it only when the hops before are up",
+            ),
+        });
+    }
+}
+
+pub async fn diagnose(paths: &PathState, uid: u32) -> Result<Vec<Diagnosis>> {
+    let mut out = Vec::new();
+    userns_sysctls(&mut out);
+    out.push(tun_device());
+    let mut sproxy = current_exe()?;
+    sproxy.set_file_name("sproxy");
+    out.push(sproxy_bin(&sproxy));
+    out.push(version_match(&sproxy));
+    buses(&mut out).await;
+    writable(&mut out, paths, uid);
+    out.push(propagation(paths)?);
+    out.push(ping_range(uid));
+    proxies(&mut out, paths);
+    Ok(out)
+}
+
+pub fn print_table(diags: &[Diagnosis]) {
+    let width = diags.iter().map(|d| d.name.len()).max().unwrap_or(0);
+    for d in diags {
+        let status = match d.status {
+            Status::Pass => "PASS".green().to_string(),
+            Status::Warn => "WARN".yellow().to_string(),
+            Status::Fail => "FAIL".red().to_string(),
+        };
+        println!("{}  {:width$}  {}", status, d.name, d.detail);
+        if let Some(h) = &d.hint {
+            println!("      {:width$}  {}", "", h.bright_black());
+        }
+    }
+}
+
+#[test]
+fn bus_verdict() {
+    let down = || Err("connection refused".to_owned());
+    assert_eq!(bus("system bus", Ok(()), true, "").status, Status::Pass);
+    assert_eq!(bus("system bus", down(), true, "").status, Status::Fail);
+    assert_eq!(bus("session bus", down(), false, "").status, Status::Warn);
+}
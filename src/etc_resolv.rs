@@ -4,17 +4,37 @@ use anyhow::Result;
 use log::warn;
 use nix::mount::{mount, umount, MsFlags};
 use tracing::info;
+
+use crate::{audit, plan::Action};
 const ETCRESOLV: &str = "/etc/resolv.conf";
+const COPY: &str = include_str!("../resolv.conf");
+
+/// What [mount_conf] does, the write and then the bind
+pub fn conf_actions() -> [Action; 2] {
+    let path = "/tmp/resolv.conf";
+    [
+        Action::WriteFile {
+            path: path.into(),
+            contents: COPY.to_owned(),
+        },
+        Action::Mount {
+            src: path.into(),
+            dst: ETCRESOLV.into(),
+        },
+    ]
+}
 
 /// run this in a mount namespace; otherwise it applies globally which is probably undesirable
 pub fn mount_conf() -> Result<()> {
-    let copy = include_str!("../resolv.conf");
+    let copy = COPY;
     let path = "/tmp/resolv.conf";
+    let [write, bind] = conf_actions();
     if fs::exists(path)? {
         let _ = fs::remove_file(path);
     }
     let mut fd = std::fs::File::create(path)?;
     fd.write_all(copy.as_bytes())?;
+    audit::record(&write);
     info!("try umount first");
     let rx = umount(ETCRESOLV);
     if rx.is_ok() {
@@ -31,6 +51,7 @@ pub fn mount_conf() -> Result<()> {
         MsFlags::MS_BIND,
         None::<&str>,
     )?;
+    audit::record(&bind);
     Ok(())
 }
 
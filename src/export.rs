@@ -0,0 +1,158 @@
+//! Renders the object graph for humans, as Graphviz DOT or Mermaid
+
+use clap::ValueEnum;
+use daggy::petgraph::visit::{EdgeRef, IntoEdgeReferences};
+
+use super::*;
+use crate::{
+    data::{EdgeI, FDRecver, Graphs, NSSlot, NodeI, ObjectNode, Relation},
+    managed::Socks2TUN,
+    systemd::UnitName,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Dot,
+    Mermaid,
+}
+
+/// Name, index, and netns inode
+fn node_label(ix: NodeI, node: &ObjectNode) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{} #{}",
+        node.name.as_deref().unwrap_or("unnamed"),
+        ix.index()
+    )];
+    if let NSSlot::Provided(n, _) = &node.main.net {
+        lines.push(format!("net {}", n.unique.ino));
+    }
+    lines
+}
+
+/// Kind, addresses, and the unit receiving FDs
+pub fn edge_label(ix: EdgeI, rel: &Relation) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    match rel {
+        Relation::Veth(v) => {
+            lines.push(format!("veth {}", v.key));
+            lines.push(format!("{} -> {}", v.ip_va, v.ip_vb));
+        }
+        Relation::SendTUN(p) => lines.push(format!(
+            "TUN {}",
+            p.creation.tun_name.as_deref().unwrap_or("unnamed")
+        )),
+        Relation::SendSocket(p) => lines.push(format!("socket {}", p.creation.addr)),
+    }
+    match rel.fd_recver() {
+        Some(FDRecver::TUN2Proxy(path)) => lines.push(Socks2TUN::new(path, ix)?.service()?),
+        Some(FDRecver::Systemd(unit)) => lines.push(unit.clone()),
+        _ => (),
+    }
+    Ok(lines)
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn mermaid_escape(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+pub fn export(graphs: &Graphs, format: ExportFormat) -> Result<String> {
+    let mut out = String::new();
+    let join = |lines: Vec<String>| -> String {
+        match format {
+            ExportFormat::Dot => lines
+                .iter()
+                .map(|l| dot_escape(l))
+                .collect::<Vec<_>>()
+                .join("\\n"),
+            ExportFormat::Mermaid => lines
+                .iter()
+                .map(|l| mermaid_escape(l))
+                .collect::<Vec<_>>()
+                .join("<br/>"),
+        }
+    };
+    match format {
+        ExportFormat::Dot => out.push_str("digraph nsproxy {\n"),
+        ExportFormat::Mermaid => out.push_str("flowchart LR\n"),
+    }
+    for ni in graphs.data.node_indices() {
+        let label = match &graphs.data[ni] {
+            Some(node) => join(node_label(ni, node)),
+            None => join(vec![format!("#{} (allocated)", ni.index())]),
+        };
+        match format {
+            ExportFormat::Dot => {
+                out += &format!("    n{} [shape=box, label=\"{}\"];\n", ni.index(), label)
+            }
+            ExportFormat::Mermaid => out += &format!("    n{}[\"{}\"]\n", ni.index(), label),
+        }
+    }
+    for edge in graphs.data.edge_references() {
+        let label = match edge.weight() {
+            Some(rel) => join(edge_label(edge.id(), rel)?),
+            None => join(vec!["(allocated)".to_owned()]),
+        };
+        let (src, dst) = (edge.source().index(), edge.target().index());
+        match format {
+            ExportFormat::Dot => out += &format!("    n{src} -> n{dst} [label=\"{label}\"];\n"),
+            ExportFormat::Mermaid => out += &format!("    n{src} -->|\"{label}\"| n{dst}\n"),
+        }
+    }
+    if let ExportFormat::Dot = format {
+        out.push_str("}\n");
+    }
+    Ok(out)
+}
+
+#[test]
+fn export_tun() -> Result<()> {
+    use crate::data::{NSGroup, NSNet, NSTrait, PassFD, TUNC};
+    use nsproxy_common::{ExactNS, NSSource, UniqueFile};
+    use std::path::PathBuf;
+
+    let mut graphs = Graphs::default();
+    let mut node = |name: Option<&str>, ino| {
+        let mut main = NSGroup::default();
+        NSNet::set(
+            &mut main,
+            ExactNS {
+                unique: UniqueFile::new(ino, 4),
+                source: NSSource::Unavail(false),
+                fp: None,
+            },
+        );
+        graphs.data.add_node(Some(ObjectNode {
+            name: name.map(ToOwned::to_owned),
+            main,
+            root: false,
+            identity: None,
+            applied: None,
+        }))
+    };
+    let src = node(Some("browser"), 4026532100);
+    let out = node(None, 4026531840);
+    graphs.data.add_edge(
+        src,
+        out,
+        Some(Relation::SendTUN(PassFD {
+            creation: TUNC {
+                layer: tun::Layer::L3,
+                tun_name: Some(PROBE_TUN.to_owned()),
+                mtu: None,
+            },
+            listener: "/tmp/proxy0.sock".into(),
+            receiver: FDRecver::TUN2Proxy(PathBuf::from("/etc/nsproxy/proxy.json")),
+        })),
+    );
+    let dot = export(&graphs, ExportFormat::Dot)?;
+    assert!(dot.contains("n0 [shape=box, label=\"browser #0\\nnet 4026532100\"];"));
+    assert!(dot.contains("n0 -> n1 [label=\"TUN tunp\\nproxy0.service\"];"));
+    let mermaid = export(&graphs, ExportFormat::Mermaid)?;
+    assert!(mermaid.contains("n1[\"unnamed #1<br/>net 4026531840\"]"));
+    assert!(mermaid.contains("n0 -->|\"TUN tunp<br/>proxy0.service\"| n1"));
+    Ok(())
+}
@@ -9,54 +9,175 @@ use std::{
         HashMap, HashSet,
     },
     ffi::OsStr,
-    io::Read,
-    os::unix::fs::chown,
-    path::PathBuf,
+    fs::{hard_link, remove_file, rename, File},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use super::*;
 use crate::{
+    audit,
     data::{
-        ExactNS, Graphs, Ix, NSGraph, NSGroup, NSNet, NSSlot, NSTrait, NodeI, ObjectNode, Relation,
-        Route, RouteNode, Validate,
+        nstypes, ExactNS, Graphs, Ix, NSGraph, NSGroup, NSNet, NSSlot, NSTrait, NodeI, ObjectNode,
+        Relation, Route, RouteNode, Validate, ValidateR,
     },
+    identity::Identity,
+    lock::{LockMode, StateLock},
     managed::{ItemCreate, ItemRM, NodeWDeps},
+    migrate,
     paths::{PathState, Paths},
+    plan::{Action, Planned},
+    sys::{atomic_write, ns_members, LinkM},
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use daggy::{
     self,
     petgraph::visit::{self, Reversed, Topo},
     stable_dag::StableDag,
     Dag,
 };
-use fs4::FileExt;
 use futures::Future;
+use linux_raw_sys::ioctl::NS_GET_USERNS;
 use netlink_ops::netlink::{nl_ctx, LinkAB, LinkKey, NLDriver, NLHandle};
-use nsproxy_common::{NSSource, PidPath::Selfproc, UniqueFile, VaCache, ValidationErr};
+use nix::{errno::Errno, sys::stat::fstat};
+use nsproxy_common::{
+    NSFrom, NSSource,
+    PidPath::{self, Selfproc},
+    UniqueFile, VaCache, ValidationErr,
+};
 use petgraph::visit::IntoNodeReferences;
-use serde_json::{from_str, to_string_pretty};
+use serde::Serialize;
+use serde_json::{from_str, from_value, to_string_pretty, to_value};
 use tracing::{debug, info, warn};
 
 pub fn find_all_netns() -> Result<HashMap<UniqueFile, PathBuf>> {
+    Ok(netns_inventory(None)?
+        .into_iter()
+        .map(|n| (n.unique, n.path))
+        .collect())
+}
+
+/// A process in the NS
+#[public]
+#[derive(Serialize, Debug, Clone)]
+struct Holder {
+    pid: pid_t,
+    comm: String,
+    uid: u32,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct NetNSInfo {
+    unique: UniqueFile,
+    /// NS file of the first holder, or the first mount
+    #[serde(skip)]
+    path: PathBuf,
+    /// The owning UserNS, when it could be queried
+    user: Option<UniqueFile>,
+    /// Interfaces seen by the first holder
+    interfaces: Option<usize>,
+    holders: Vec<Holder>,
+    /// nsfs binds of it under the binds dir
+    mounts: Vec<PathBuf>,
+}
+
+/// Every NetNS held by a process we can read, with its processes,
+/// and those held by nsfs binds under binds, if given.
+/// Binds are only visible in the mount NS they are in, as in [NSGroup::mounted]
+pub fn netns_inventory(binds: Option<&Path>) -> Result<Vec<NetNSInfo>> {
     let netk = OsStr::new("net");
-    let mut uf_proc = HashMap::new();
+    let mut found: Vec<NetNSInfo> = Vec::new();
+    let mut by_uf = HashMap::new();
     let procs = procfs::process::all_processes()?;
     for proc in procs {
-        let proc = proc?;
-        let mut ns = proc.namespaces()?;
-        if let Some(net) = ns.0.remove(netk) {
-            let uf = UniqueFile::new(net.identifier, net.device_id);
-            match uf_proc.entry(uf) {
-                Vacant(e) => {
-                    e.insert(net.path);
-                }
-                Occupied(e) => continue,
+        // Processes exit during the walk, and those of other users are not readable
+        let Ok(proc) = proc else {
+            continue;
+        };
+        let Ok(mut ns) = proc.namespaces() else {
+            continue;
+        };
+        let Some(net) = ns.0.remove(netk) else {
+            continue;
+        };
+        let Some(holder) = Holder::of(proc.pid) else {
+            continue;
+        };
+        let uf = UniqueFile::new(net.identifier, net.device_id);
+        match by_uf.entry(uf) {
+            Vacant(e) => {
+                e.insert(found.len());
+                found.push(NetNSInfo {
+                    unique: uf,
+                    user: owner_userns(&net.path),
+                    interfaces: interface_count(proc.pid),
+                    path: net.path,
+                    holders: vec![holder],
+                    mounts: Vec::new(),
+                });
+            }
+            Occupied(e) => found[*e.get()].holders.push(holder),
+        }
+    }
+    let Some(binds) = binds else {
+        return Ok(found);
+    };
+    for m in proc_mounts::MountIter::new()? {
+        let m = m?;
+        if m.fstype != "nsfs" || !m.dest.starts_with(binds) || m.dest.file_name() != Some(netk) {
+            continue;
+        }
+        let Ok(st) = nix::sys::stat::stat(&m.dest) else {
+            continue;
+        };
+        match by_uf.entry(st.into()) {
+            Vacant(e) => {
+                e.insert(found.len());
+                found.push(NetNSInfo {
+                    unique: *e.key(),
+                    user: owner_userns(&m.dest),
+                    interfaces: None,
+                    path: m.dest.clone(),
+                    holders: Vec::new(),
+                    mounts: vec![m.dest],
+                });
             }
+            Occupied(e) => found[*e.get()].mounts.push(m.dest),
         }
     }
-    Ok(uf_proc)
+    Ok(found)
+}
+
+#[public]
+impl Holder {
+    /// None if the process exited
+    fn of(pid: pid_t) -> Option<Self> {
+        let proc = procfs::process::Process::new(pid).ok()?;
+        Some(Self {
+            pid,
+            comm: proc.stat().ok()?.comm,
+            uid: proc.uid().ok()?,
+        })
+    }
+}
+
+fn owner_userns(path: &Path) -> Option<UniqueFile> {
+    let f = File::open(path).ok()?;
+    let fd = unsafe { libc::ioctl(f.as_raw_fd(), NS_GET_USERNS.into()) };
+    if fd < 0 {
+        return None;
+    }
+    let fu = unsafe { OwnedFd::from_raw_fd(fd) };
+    Some(fstat(fu.as_raw_fd()).ok()?.into())
+}
+
+/// Lines of /proc/<pid>/net/dev, less the two of the header
+fn interface_count(pid: pid_t) -> Option<usize> {
+    let dev = std::fs::read_to_string(format!("/proc/{}/net/dev", pid)).ok()?;
+    Some(dev.lines().count().saturating_sub(2))
 }
 
 #[derive(PartialEq, Eq)]
@@ -124,6 +245,19 @@ pub struct RM {
     /// Links found
     links: HashSet<LinkKey>,
     rm: bool,
+    /// Why the node is removed
+    reasons: Vec<String>,
+}
+
+impl RM {
+    fn remove(&mut self, reason: impl Into<String>) {
+        self.rm = true;
+        self.reasons.push(reason.into());
+    }
+    /// Reasons, if the node is to be removed
+    pub fn reasons(&self) -> Option<&[String]> {
+        self.rm.then_some(self.reasons.as_slice())
+    }
 }
 
 pub trait RMable {
@@ -152,35 +286,96 @@ fn insert_rm_ref<'b>(map: &'b mut HashMap<NodeI, RM>, key: &NodeI) -> &'b mut RM
 }
 
 impl Graphs {
-    /// remove: a hashset containing nodes scheduled for remove, by method prune.
+    /// Fixes /proc/ paths made unavailable by processes dying,
+    /// by finding existing processes with the same netns.
+    /// Other NSes anchored at the process move along, if the found one is in them.
+    /// Nodes left with no process are scheduled for removal.
     pub async fn clean_proc(&mut self, remove: &mut HashMap<NodeI, RM>) -> Result<()> {
-        todo!();
-        // try to fix /proc/ paths that are made unavailable by processes dying
-        // by finding existing processes with same netns
-        let mut uf_proc = find_all_netns()?;
+        let mut holder: HashMap<UniqueFile, pid_t> = netns_inventory(None)?
+            .into_iter()
+            .map(|n| (n.unique, n.holders[0].pid))
+            .collect();
         let mut indices = Vec::with_capacity(self.data.node_count());
         indices.extend(self.data.node_indices());
         for ni in indices {
-            if let Some(node) = &mut self.data[ni] {
-                match &mut node.main.net {
-                    NSSlot::Provided(en, _ty) => match &mut en.source {
-                        NSSource::Path(pt) => {
-                            if pt.starts_with("/proc/") && !pt.exists() {
-                                // Each node maps to one unique NS, so we can just take the owned
-                                if let Some(uf) = uf_proc.remove(&en.unique) {
-                                    *pt = uf;
-                                } else {
-                                    insert_rm(remove, &ni, ());
-                                }
-                            }
+            let Some(node) = &mut self.data[ni] else {
+                continue;
+            };
+            let NSSlot::Provided(en, _ty) = &node.main.net else {
+                continue;
+            };
+            let NSSource::Path(pt) = &en.source else {
+                continue;
+            };
+            // The PID may have been reused by a process in another NS
+            let held = match nix::sys::stat::stat(pt.as_path()) {
+                Ok(st) => en.unique.validate(&st).is_ok(),
+                // Not ours to judge, as in prune
+                Err(Errno::EACCES | Errno::EPERM) => true,
+                Err(_) => false,
+            };
+            let Some(old) = en.anchor() else {
+                continue;
+            };
+            if held {
+                continue;
+            }
+            // Each node maps to one unique NS, so we can just take the owned
+            if let Some(new) = holder.remove(&en.unique) {
+                info!("Node {} moved from {} to {}", ni.index(), old, new);
+                node.main.rehome(old, new)?;
+            } else {
+                info!("No process holds the NetNS of node {}", ni.index());
+                insert_rm_ref(remove, &ni).remove("no process holds the NS");
+            }
+        }
+        Ok(())
+    }
+    /// Processes whose exit may leave nodes to collect
+    pub fn holders(&self) -> Vec<pid_t> {
+        let mut pids = Vec::new();
+        for node in self
+            .data
+            .node_indices()
+            .filter_map(|ni| self.data[ni].as_ref())
+        {
+            if let NSSlot::Provided(en, _) = &node.main.net {
+                match &en.source {
+                    NSSource::Pid(p) => pids.push(*p),
+                    NSSource::Path(pt) if pt.starts_with("/proc/") => {
+                        if let Some(p) = pt.iter().nth(2).and_then(|c| c.to_str()?.parse().ok()) {
+                            pids.push(p)
                         }
-                        _ => (),
-                    },
+                    }
                     _ => (),
                 }
             }
         }
-        Ok(())
+        pids
+    }
+    /// Processes of the node, all in its own PID NS if it has one, or those in its NetNS.
+    /// Returns the kind of NS they were found by.
+    pub fn processes(
+        &self,
+        node: NodeI,
+        ctx: &NSGroup<ExactNS>,
+    ) -> Result<(&'static str, Vec<pid_t>)> {
+        let obj = self.data[node]
+            .as_ref()
+            .ok_or(anyhow!("Node {} does not exist", node.index()))?;
+        if let NSSlot::Provided(pid, _) = &obj.main.pid {
+            if ctx.pid.must()?.unique != pid.unique {
+                return Ok(("pid", ns_members(&pid.unique, "pid")?));
+            }
+        }
+        let net = obj.main.net.must()?;
+        if ctx.net.must()?.unique == net.unique {
+            bail!(
+                "Node {} shares the NetNS with us. Its processes are not told apart",
+                self.node_label(node)
+            );
+        }
+        Ok(("net", ns_members(&net.unique, "net")?))
     }
     pub async fn node_rm<'f, S>(
         &mut self,
@@ -202,7 +397,7 @@ impl Graphs {
                 let nodew = self.nodewdeps(*ni)?;
                 let rm = insert_rm_ref(remove, &ni);
                 check_veths(nl, &nodew, &ctx, &mut rm.links).await?;
-                rm.rm = true;
+                rm.remove("requested");
             } else {
                 warn!("skipped {:?} for it's None", ni)
             }
@@ -220,6 +415,7 @@ impl Graphs {
     where
         for<'a, 'b> NodeWDeps<'a, 'b>: ItemRM<Serv = S>,
     {
+        self.clean_proc(remove).await?;
         for (ni, node) in self.data.node_references() {
             if let Some(k) = node {
                 let rx = k.main.net.validate(va, &ctx);
@@ -229,10 +425,11 @@ impl Graphs {
                     let nodew = self.nodewdeps(ni)?;
                     let zerodep = nodew.1.len() == 0;
                     let rm = insert_rm_ref(remove, &ni);
-                    if let FResult::Remove(_) = check_veths(nl, &nodew, &ctx, &mut rm.links).await?
+                    if let FResult::Remove(reason) =
+                        check_veths(nl, &nodew, &ctx, &mut rm.links).await?
                     {
                         info!("Removing NS node {} because veth is missing", k.main.key());
-                        rm.rm = true;
+                        rm.remove(reason);
                     }
                     if matches!(verr, ValidationErr::FileNonExistProc) {
                         if zerodep {
@@ -241,12 +438,12 @@ impl Graphs {
                                 k.main.key(),
                                 verr
                             );
-                            rm.rm = true;
+                            rm.remove(format!("{} and no dependencies", verr));
                         }
                     }
                     if !matches!(verr, ValidationErr::Permission) {
                         info!("Removing NS node {} for {}", k.main.key(), verr);
-                        rm.rm = true;
+                        rm.remove(verr.to_string());
                     }
                 } else {
                     debug!("prune check, {:?} Net NS, {:?}", ni, rx)
@@ -260,9 +457,10 @@ impl Graphs {
     pub async fn do_prune<'f, S>(
         &mut self,
         ctx: &NSGroup<ExactNS>,
+        paths: &PathState,
         serv: &S,
         remove: HashMap<NodeI, RM>,
-        nl: &mut NLDriver,
+        nl: &mut impl LinkM,
     ) -> Result<()>
     where
         for<'a, 'b> NodeWDeps<'a, 'b>: ItemRM<Serv = S>,
@@ -271,56 +469,147 @@ impl Graphs {
             let nodew = self.nodewdeps(*ni)?;
             if rm.rm {
                 for link in &rm.links {
-                    info!("Remove {:?}", &link);
-                    nl.remove_link(&link).await?;
+                    nl.remove(link).await?;
                 }
                 nodew.remove(serv).await?;
-                self.map.remove(&nodew.0.item.main.key());
+                let act = Action::RemoveNode {
+                    ix: *ni,
+                    name: nodew.0.item.name.clone(),
+                    reasons: rm.reasons.clone(),
+                };
+                let key = nodew.0.item.main.key();
+                self.record(&act);
+                self.map.remove(&key);
                 self.data.remove_node(*ni);
+                self.done(&act);
+                // A node of the same index must not inherit them
+                Identity::remove(paths, *ni, self)?;
             }
         }
+        self.rebuild_route();
         Ok(())
     }
     pub fn load(st: &str) -> Result<Self> {
-        let g: Self = from_str(st)?;
+        let value = migrate::migrate(from_str(st)?)?;
+        let mut g: Self = from_value(value)?;
+        g.rebuild_route();
         Ok(g)
     }
     pub fn load_file(path: &PathState) -> Result<Self> {
+        Self::load_locked(path, LockMode::Exclusive)
+    }
+    /// Takes the lock without loading, so that the state can be rebuilt even if the file is broken
+    pub fn load_blank(path: &PathState) -> Result<Self> {
+        let lock = StateLock::acquire(&Self::lockpath(path), LockMode::Exclusive)?;
+        audit::open(path);
+        Ok(Self {
+            lock: Some(lock),
+            ..Default::default()
+        })
+    }
+    /// For inspection. Concurrent readers do not block each other, but the graphs must not be written.
+    pub fn load_file_ro(path: &PathState) -> Result<Self> {
+        Self::load_locked(path, LockMode::Shared)
+    }
+    fn load_locked(path: &PathState, mode: LockMode) -> Result<Self> {
         let gp = Self::path(path);
         info!("Load graphs from {:?}", &gp);
+        let lock = StateLock::acquire(&Self::lockpath(path), mode)?;
+        audit::open(path);
         let mut thing: Option<Self> = None;
-        let file = if gp.exists() {
-            let mut file = std::fs::File::open(&gp)?;
-            file.try_lock_exclusive()
-                .map_err(|_| anyhow!("State file locked"))?;
-            let mut st = Default::default();
-            file.read_to_string(&mut st)?;
-            let k = Self::load(&st);
-            if let Ok(stuff) = k {
-                thing = Some(stuff)
-            } else if st.trim().is_empty() {
-                // Ignore
-            } else {
-                log::warn!("Corrupted state file. Reset");
+        if gp.exists() {
+            let st = std::fs::read_to_string(&gp)?;
+            if !st.trim().is_empty() {
+                match Self::load(&st) {
+                    Ok(stuff) => thing = Some(stuff),
+                    Err(e) => {
+                        // Resetting would orphan the mounts, veths and units it records
+                        let backup = gp.with_extension(format!(
+                            "json.broken-{}",
+                            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+                        ));
+                        std::fs::copy(&gp, &backup)?;
+                        bail!(
+                            "Can not load state file {:?}, {:?}. A copy is kept at {:?}. Earlier generations are listed by nsproxy state ls",
+                            gp,
+                            e,
+                            backup
+                        );
+                    }
+                }
             }
-            file
-        } else {
-            let f = std::fs::File::create(&gp)?;
-            assert!(gp.exists());
-            f.try_lock_exclusive()
-                .map_err(|_| anyhow!("State file locked"))?;
-            f
-        };
+        }
         let mut k = thing.unwrap_or_default();
-        k.file = Some(file);
+        k.lock = Some(lock);
         Ok(k)
     }
+    /// The state file is replaced on writes, so the lock is kept on a separate file
+    pub fn lockpath(path: &PathState) -> PathBuf {
+        path.state.join("graphs.lock")
+    }
     pub fn dump_file(&self, path: &PathState, wuid: u32) -> Result<()> {
         let pa = Self::path(path);
+        if let Some(LockMode::Shared) = self.lock.as_ref().map(|l| l.mode()) {
+            bail!("Graphs loaded read-only can not be written");
+        }
+        let act = Action::WriteState(pa.clone());
+        if self.record(&act) {
+            return Ok(());
+        }
+        let mut value = to_value(self)?;
+        migrate::tag(&mut value)?;
+        let bytes = serde_json::to_vec_pretty(&value)?;
+        // Writes that change nothing would push the generations out
+        if std::fs::read(&pa).is_ok_and(|cur| cur == bytes) {
+            debug!("Graphs at {:?} are unchanged", &pa);
+            return Ok(());
+        }
         log::info!("Dump graphs to {:?}", &pa);
-        let file = std::fs::File::create(&pa)?;
-        chown(&pa, Some(wuid), None)?;
-        serde_json::to_writer_pretty(&file, self)?;
+        Self::rotate(path)?;
+        atomic_write(&pa, &bytes, Some(wuid))?;
+        self.done(&act);
+        Ok(())
+    }
+    /// Keeps the last generations of the state file. Generation 1 is the latest.
+    fn rotate(path: &PathState) -> Result<()> {
+        let pa = Self::path(path);
+        if !pa.exists() {
+            return Ok(());
+        }
+        for n in (1..STATE_GENERATIONS).rev() {
+            let older = Self::generation(path, n);
+            if older.exists() {
+                rename(&older, Self::generation(path, n + 1))?;
+            }
+        }
+        let latest = Self::generation(path, 1);
+        if latest.exists() {
+            remove_file(&latest)?;
+        }
+        hard_link(&pa, &latest)?;
+        Ok(())
+    }
+    pub fn generation(path: &PathState, n: usize) -> PathBuf {
+        Self::path(path).with_extension(format!("json.{}", n))
+    }
+    /// Existing generations, latest first
+    pub fn generations(path: &PathState) -> Vec<(usize, PathBuf)> {
+        (1..=STATE_GENERATIONS)
+            .map(|n| (n, Self::generation(path, n)))
+            .filter(|(_, p)| p.exists())
+            .collect()
+    }
+    /// Rolls back to a generation. The state being replaced becomes generation 1.
+    pub fn restore(path: &PathState, n: usize, wuid: u32) -> Result<()> {
+        let _lock = StateLock::acquire(&Self::lockpath(path), LockMode::Exclusive)?;
+        let backup = Self::generation(path, n);
+        let st = std::fs::read_to_string(&backup)
+            .map_err(|e| anyhow!("Generation {} at {:?}, {}", n, backup, e))?;
+        // Refuse what can not be loaded
+        Self::load(&st)?;
+        Self::rotate(path)?;
+        atomic_write(&Self::path(path), st.as_bytes(), Some(wuid))?;
+        info!("Restored {:?}", backup);
         Ok(())
     }
     pub fn path(path: &PathState) -> PathBuf {
@@ -328,10 +617,102 @@ impl Graphs {
     }
 }
 
-impl Drop for Graphs {
-    fn drop(&mut self) {
-        if let Some(ref f) = self.file {
-            f.unlock().unwrap();
+/// A node of our NSes, as if recorded from a process that exited, with its PID
+#[cfg(test)]
+fn recorded_dead(fp: Option<nsproxy_common::Fingerprint>) -> Result<(Graphs, NodeI, pid_t)> {
+    let ours = nix::unistd::getpid().as_raw();
+    let mut child = std::process::Command::new("true").spawn()?;
+    child.wait()?;
+    let dead = child.id() as pid_t;
+    let mut main = NSGroup::default();
+    for (kind, set) in nstypes() {
+        // Older kernels lack some
+        if let Ok(mut ns) = ExactNS::from_source((PidPath::N(ours), kind)) {
+            ns.source = NSSource::Path(format!("/proc/{}/ns/{}", dead, kind).into());
+            ns.fp = fp;
+            set(&mut main, ns);
         }
     }
+    let mut graphs = Graphs::default();
+    let ni = graphs.data.add_node(Some(ObjectNode {
+        name: None,
+        main,
+        root: false,
+        identity: None,
+        applied: None,
+    }));
+    Ok((graphs, ni, dead))
+}
+
+#[test]
+fn rehome_all() -> Result<()> {
+    let (mut graphs, ni, dead) = recorded_dead(None)?;
+    let mut remove = HashMap::new();
+    crate::blockon::block_on(graphs.clean_proc(&mut remove))??;
+    assert!(remove.is_empty());
+    let main = &graphs.data[ni].as_ref().unwrap().main;
+    let new = main.net.must()?.anchor().unwrap();
+    assert_ne!(new, dead);
+    // Slots the found process shares are moved too
+    let uts = main.uts.must()?;
+    if ExactNS::from_source((PidPath::N(new), "uts"))?.unique == uts.unique {
+        assert_eq!(uts.anchor(), Some(new));
+    }
+    Ok(())
+}
+
+#[test]
+fn rehome_validate() -> Result<()> {
+    // The start time of the exited process, which no process has now
+    let fp = nsproxy_common::Fingerprint {
+        start: Some(1),
+        cookie: None,
+    };
+    let (mut graphs, ni, _) = recorded_dead(Some(fp))?;
+    crate::blockon::block_on(graphs.clean_proc(&mut HashMap::new()))??;
+    let ctx = NSGroup::proc_path(Selfproc, None)?;
+    let main = &graphs.data[ni].as_ref().unwrap().main;
+    assert_eq!(
+        main.net.validate(&mut VaCache::default(), &ctx)?,
+        ValidateR::Pass
+    );
+    Ok(())
+}
+
+#[test]
+fn rotate_restore() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("nsproxy-state-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let paths = PathState {
+        config: dir.clone(),
+        binds: None,
+        priv_binds: dir.clone(),
+        state: dir.clone(),
+    };
+    let uid = nix::unistd::getuid().as_raw();
+    let nodes = |n: usize| -> Result<usize> {
+        let st = std::fs::read_to_string(Graphs::generation(&paths, n))?;
+        Ok(Graphs::load(&st)?.data.node_count())
+    };
+    let mut graphs = Graphs::default();
+    for _ in 0..3 {
+        graphs.data.add_node(Some(ObjectNode {
+            name: None,
+            main: NSGroup::default(),
+            root: false,
+            identity: None,
+            applied: None,
+        }));
+        graphs.dump_file(&paths, uid)?;
+    }
+    // Unchanged, so nothing shifts
+    graphs.dump_file(&paths, uid)?;
+    assert_eq!((nodes(1)?, nodes(2)?), (2, 1));
+    assert!(!Graphs::generation(&paths, 3).exists());
+    Graphs::restore(&paths, 2, uid)?;
+    let st = std::fs::read_to_string(Graphs::path(&paths))?;
+    assert_eq!(Graphs::load(&st)?.data.node_count(), 1);
+    assert_eq!((nodes(1)?, nodes(2)?, nodes(3)?), (3, 2, 1));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
 }
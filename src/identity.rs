@@ -0,0 +1,270 @@
+//! Per-node hostname and machine-id, so that apps can not link nodes by them.
+//! X clients find their cookie in Xauthority by hostname, so the node gets a copy
+//! whose local entries match any hostname.
+
+use std::{
+    env::var,
+    fs::{create_dir_all, read, remove_dir_all},
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::bail;
+use nix::{
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+    unistd::{gethostname, sethostname},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::*;
+use crate::{
+    audit,
+    data::NodeI,
+    paths::PathState,
+    plan::{Action, Planned},
+    sys::atomic_write,
+};
+
+#[public]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+struct Identity {
+    hostname: Option<String>,
+    /// A machine-id of the node is bound over /etc/machine-id, in a mnt NS of the node
+    machine_id: bool,
+}
+
+/// Where apps read the machine-id
+pub const MACHINE_ID: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+/// Something common, that tells nothing
+pub fn random_hostname() -> String {
+    const NAMES: [&str; 4] = ["desktop", "laptop", "workstation", "pc"];
+    let mut rng = rand::thread_rng();
+    format!(
+        "{}-{:04x}",
+        NAMES[rng.gen_range(0..NAMES.len())],
+        rng.gen::<u16>()
+    )
+}
+
+#[public]
+impl Identity {
+    /// `random` picks a hostname
+    fn new(hostname: Option<String>, machine_id: bool) -> Self {
+        let hostname = hostname.map(|h| if h == "random" { random_hostname() } else { h });
+        Self {
+            hostname,
+            machine_id,
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.hostname.is_none() && !self.machine_id
+    }
+    fn dir(paths: &PathState, ix: NodeI) -> PathBuf {
+        paths.state.join("identity").join(ix.index().to_string())
+    }
+    fn machine_id_path(paths: &PathState, ix: NodeI) -> PathBuf {
+        Self::dir(paths, ix).join("machine-id")
+    }
+    fn xauth_path(paths: &PathState, ix: NodeI) -> PathBuf {
+        Self::dir(paths, ix).join("Xauthority")
+    }
+    /// Sets the hostname of the UTS NS we are in
+    fn set_hostname(&self) -> Result<()> {
+        if let Some(name) = &self.hostname {
+            info!("Set hostname to {}", name);
+            sethostname(name)?;
+        }
+        Ok(())
+    }
+    /// Writes the files of the node, owned by uid. Call it before the hostname changes.
+    fn prepare(&self, paths: &PathState, ix: NodeI, uid: u32) -> Result<()> {
+        let files = self.files(paths, ix, uid)?;
+        let dir = Self::dir(paths, ix);
+        // Left by a removed node of the same index
+        let _ = remove_dir_all(&dir);
+        if files.is_empty() {
+            return Ok(());
+        }
+        create_dir_all(&dir)?;
+        for (pa, contents) in files {
+            atomic_write(&pa, &contents, Some(uid))?;
+            audit::record(&Action::write_file(&pa, &contents));
+        }
+        Ok(())
+    }
+    /// Removes the files of a removed node
+    fn remove(paths: &PathState, ix: NodeI, plan: &impl Planned) -> Result<()> {
+        let dir = Self::dir(paths, ix);
+        if !dir.exists() {
+            return Ok(());
+        }
+        let act = Action::RemoveFile(dir.clone());
+        if plan.record(&act) {
+            return Ok(());
+        }
+        remove_dir_all(&dir)?;
+        plan.done(&act);
+        Ok(())
+    }
+    /// The files [Identity::prepare] writes. Nothing is changed
+    fn files(&self, paths: &PathState, ix: NodeI, uid: u32) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+        let mut files = Vec::new();
+        if self.machine_id {
+            let id: u128 = rand::thread_rng().gen();
+            files.push((
+                Self::machine_id_path(paths, ix),
+                format!("{:032x}\n", id).into_bytes(),
+            ));
+        }
+        if self.hostname.is_some() {
+            match xauth_source(uid) {
+                Some(src) => {
+                    let host = gethostname()?;
+                    let entries = parse_xauth(&read(&src)?)?;
+                    let entries = wild_local(entries, host.as_encoded_bytes());
+                    files.push((Self::xauth_path(paths, ix), write_xauth(&entries)));
+                }
+                None => info!("No Xauthority found. X clients may not connect"),
+            }
+        }
+        Ok(files)
+    }
+    /// Gives the process about to become the node a mnt NS of its own, for the machine-id
+    fn unshare_mnt() -> Result<()> {
+        unshare(CloneFlags::CLONE_NEWNS)?;
+        // Keep the bind from propagating back
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_SLAVE | MsFlags::MS_REC,
+            None::<&str>,
+        )?;
+        Ok(())
+    }
+    /// Binds the machine-id of the node in the mnt NS we are in, which is that of the node.
+    /// Processes entering the node later see it too.
+    fn bind_machine_id(paths: &PathState, ix: NodeI) -> Result<()> {
+        let mid = Self::machine_id_path(paths, ix);
+        for target in MACHINE_ID {
+            if Path::new(target).exists() {
+                mount(
+                    Some(&mid),
+                    target,
+                    None::<&str>,
+                    MsFlags::MS_BIND,
+                    None::<&str>,
+                )?;
+            }
+        }
+        Ok(())
+    }
+    /// Points processes about to be spawned to the Xauthority
+    fn apply(paths: &PathState, ix: NodeI, cmd: &mut Command) -> Result<()> {
+        let xauth = Self::xauth_path(paths, ix);
+        if xauth.exists() {
+            cmd.env("XAUTHORITY", xauth);
+        }
+        Ok(())
+    }
+}
+
+/// Xauthority of the user, from the environment, or the default one
+fn xauth_source(uid: u32) -> Option<PathBuf> {
+    if let Ok(pa) = var("XAUTHORITY") {
+        return Some(pa.into());
+    }
+    let user = uzers::get_user_by_uid(uid)?;
+    let pa = uzers::os::unix::UserExt::home_dir(&user).join(".Xauthority");
+    pa.exists().then_some(pa)
+}
+
+#[public]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct XauthEntry {
+    family: u16,
+    address: Vec<u8>,
+    number: Vec<u8>,
+    name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Entries are the family, then four fields, each prefixed with a big endian u16 length
+fn parse_xauth(mut buf: &[u8]) -> Result<Vec<XauthEntry>> {
+    fn u16_be(buf: &mut &[u8]) -> Result<u16> {
+        let mut b = [0; 2];
+        buf.read_exact(&mut b)?;
+        Ok(u16::from_be_bytes(b))
+    }
+    fn field(buf: &mut &[u8]) -> Result<Vec<u8>> {
+        let len = u16_be(buf)? as usize;
+        if buf.len() < len {
+            bail!("Xauthority is truncated");
+        }
+        let (f, rest) = buf.split_at(len);
+        *buf = rest;
+        Ok(f.to_vec())
+    }
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        entries.push(XauthEntry {
+            family: u16_be(&mut buf)?,
+            address: field(&mut buf)?,
+            number: field(&mut buf)?,
+            name: field(&mut buf)?,
+            data: field(&mut buf)?,
+        });
+    }
+    Ok(entries)
+}
+
+fn write_xauth(entries: &[XauthEntry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for en in entries {
+        buf.extend(en.family.to_be_bytes());
+        for f in [&en.address, &en.number, &en.name, &en.data] {
+            buf.extend((f.len() as u16).to_be_bytes());
+            buf.extend(f);
+        }
+    }
+    buf
+}
+
+/// Local entries of the host match any hostname
+fn wild_local(entries: Vec<XauthEntry>, host: &[u8]) -> Vec<XauthEntry> {
+    entries
+        .into_iter()
+        .map(|mut en| {
+            if en.family == FAMILY_LOCAL && en.address == host {
+                en.family = FAMILY_WILD;
+            }
+            en
+        })
+        .collect()
+}
+
+#[test]
+fn xauth_wild() -> Result<()> {
+    let entry = |family, address: &str| XauthEntry {
+        family,
+        address: address.into(),
+        number: b"0".to_vec(),
+        name: b"MIT-MAGIC-COOKIE-1".to_vec(),
+        data: vec![7; 16],
+    };
+    let entries = vec![entry(FAMILY_LOCAL, "myhost"), entry(0, "\x7f\0\0\x01")];
+    let parsed = parse_xauth(&write_xauth(&entries))?;
+    assert_eq!(parsed, entries);
+    let wild = wild_local(parsed, b"myhost");
+    assert_eq!(wild[0].family, FAMILY_WILD);
+    assert_eq!(wild[1], entries[1]);
+    assert!(parse_xauth(&[1, 0, 0, 9]).is_err());
+    Ok(())
+}
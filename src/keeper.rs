@@ -0,0 +1,192 @@
+//! Keeper of a node. It holds the pidfd of the process the node was made from, and FDs of its NSes,
+//! and hands them out over a unix socket.
+//! Held NSes are not freed, so their inodes can not be reused, and entering through them
+//! can not land in a process that took over the PID. The keeper exits with the process.
+
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, remove_file, File},
+    io::{Read, Write},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::bail;
+use netlink_ops::rtnetlink::netlink_packet_utils::byteorder::{
+    BigEndian, ReadBytesExt, WriteBytesExt,
+};
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        socket::{getsockopt, sockopt::PeerCredentials},
+        stat::fstat,
+    },
+    unistd::{fork, geteuid, getuid, setsid, ForkResult},
+};
+use passfd::FdPassingExt;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::*;
+use crate::{
+    data::{nstypes, proc_start, ExactNS, NodeI, UniqueFile},
+    paths::PathState,
+};
+
+/// Sent by the keeper before the FDs, which follow in the order of `kinds`.
+/// It is prefixed with its length, so that reading it does not consume the FDs
+#[derive(Serialize, Deserialize, Debug)]
+struct Hello {
+    pid: pid_t,
+    start: u64,
+    kinds: Vec<String>,
+}
+
+#[public]
+struct HeldNS {
+    pid: pid_t,
+    start: u64,
+    fds: HashMap<String, (UniqueFile, OwnedFd)>,
+}
+
+pub fn sock_path(paths: &PathState, ix: NodeI) -> PathBuf {
+    paths
+        .state
+        .join("keeper")
+        .join(format!("{}.sock", ix.index()))
+}
+
+/// Starts a keeper for the node, and returns once it listens
+pub fn spawn(paths: &PathState, ix: NodeI, pid: pid_t) -> Result<()> {
+    // The daemon outlives us, and must not hold our terminal or pipes open
+    let st = Command::new(std::env::current_exe()?)
+        .args(["keep", &ix.index().to_string(), &pid.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !st.success() {
+        bail!("keeper of node {} failed, {}", ix.index(), st);
+    }
+    Ok(())
+}
+
+/// Opens the NSes of pid, listens, then detaches from the caller
+pub fn keep(paths: &PathState, ix: NodeI, pid: pid_t) -> Result<()> {
+    let pidfd = unsafe { pidfd::PidFd::open(pid, 0) }?;
+    let start = proc_start(pid)?;
+    let mut held = Vec::new();
+    for kind in nstypes().into_keys() {
+        match File::open(format!("/proc/{}/ns/{}", pid, kind)) {
+            Ok(f) => held.push((kind.to_owned(), OwnedFd::from(f))),
+            // Older kernels lack some
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // The PID may have been taken over between the opens
+    if proc_start(pid)? != start {
+        bail!("process {} exited while its NSes were being opened", pid);
+    }
+    let pa = sock_path(paths, ix);
+    create_dir_all(pa.parent().unwrap())?;
+    let _ = remove_file(&pa);
+    let listener = UnixListener::bind(&pa)?;
+    match unsafe { fork() }? {
+        ForkResult::Parent { .. } => return Ok(()),
+        ForkResult::Child => (),
+    }
+    setsid()?;
+    info!(
+        "Keeping {} NSes of {} for node {}",
+        held.len(),
+        pid,
+        ix.index()
+    );
+    let hello = Hello {
+        pid,
+        start,
+        kinds: held.iter().map(|(k, _)| k.clone()).collect(),
+    };
+    loop {
+        let mut polls = [
+            PollFd::new(&pidfd, PollFlags::POLLIN),
+            PollFd::new(&listener, PollFlags::POLLIN),
+        ];
+        match poll(&mut polls, -1) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            rx => rx?,
+        };
+        if polls[0].revents().is_some_and(|r| !r.is_empty()) {
+            break;
+        }
+        let (conn, _) = listener.accept()?;
+        if let Err(e) = serve(&conn, &hello, &held) {
+            warn!("keeper of node {}, {}", ix.index(), e);
+        }
+    }
+    info!("Process {} exited", pid);
+    let _ = remove_file(&pa);
+    Ok(())
+}
+
+fn serve(mut conn: &UnixStream, hello: &Hello, held: &[(String, OwnedFd)]) -> Result<()> {
+    let cred = getsockopt(conn, PeerCredentials)?;
+    if ![0, getuid().as_raw(), geteuid().as_raw()].contains(&cred.uid()) {
+        bail!("refused uid {}", cred.uid());
+    }
+    let buf = serde_json::to_vec(hello)?;
+    conn.write_u32::<BigEndian>(buf.len().try_into()?)?;
+    conn.write_all(&buf)?;
+    for (_, fd) in held {
+        conn.send_fd(fd.as_raw_fd())?;
+    }
+    Ok(())
+}
+
+#[public]
+impl HeldNS {
+    /// FDs from the keeper of the node, if it is running
+    fn connect(paths: &PathState, ix: NodeI) -> Option<Self> {
+        match Self::connect_(paths, ix) {
+            Ok(h) => Some(h),
+            Err(e) => {
+                info!("No keeper for node {}, {}", ix.index(), e);
+                None
+            }
+        }
+    }
+    fn connect_(paths: &PathState, ix: NodeI) -> Result<Self> {
+        let mut conn = UnixStream::connect(sock_path(paths, ix))?;
+        let mut buf = vec![0; conn.read_u32::<BigEndian>()? as usize];
+        conn.read_exact(&mut buf)?;
+        let hello: Hello = serde_json::from_slice(&buf)?;
+        let mut fds = HashMap::new();
+        for kind in hello.kinds {
+            let fd = unsafe { OwnedFd::from_raw_fd(conn.recv_fd()?) };
+            fds.insert(kind, (fstat(fd.as_raw_fd())?.into(), fd));
+        }
+        Ok(Self {
+            pid: hello.pid,
+            start: hello.start,
+            fds,
+        })
+    }
+    /// The held FD of the kind, if it is the NS
+    fn get(&self, kind: &str, ns: &ExactNS) -> Option<BorrowedFd> {
+        let (uf, fd) = self.fds.get(kind)?;
+        if *uf != ns.unique {
+            return None;
+        }
+        if let Some(start) = ns.fp.and_then(|f| f.start) {
+            if ns.anchor() == Some(self.pid) && start != self.start {
+                return None;
+            }
+        }
+        Some(fd.as_fd())
+    }
+}
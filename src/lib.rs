@@ -13,16 +13,27 @@
 #![feature(impl_trait_in_fn_trait_return)]
 #![feature(async_closure)]
 
+pub mod audit;
 pub mod blockon;
 pub mod data;
+pub mod doctor;
 pub mod etc_resolv;
+pub mod export;
 pub mod flatpak;
 pub mod graph;
+pub mod identity;
+pub mod keeper;
+pub mod lock;
 pub mod managed;
+pub mod migrate;
 pub mod paths;
+pub mod plan;
 pub mod probe;
+pub mod recover;
+pub mod report;
 pub mod sys;
 pub mod systemd;
+pub mod topology;
 pub mod watcher;
 
 use std::{borrow::Cow, path::Path};
@@ -48,4 +59,9 @@ pub macro aok() {
 pub const PROBE_TUN: &str = "tunp";
 pub const UID_HINT_VAR: &str = "NSPROXY_UID";
 pub const PATH_VAR: &str = "NSPROXY_PATHS";
+/// Seconds to wait for a state lock
+pub const LOCK_TIMEOUT_VAR: &str = "NSPROXY_LOCK_TIMEOUT";
 pub const DEFAULT_MTU: u32 = 9000;
+/// Generations of the state file kept as backups
+pub const STATE_GENERATIONS: usize = 5;
+pub const GIT_SHA: &str = env!("VERGEN_GIT_SHA");
@@ -0,0 +1,174 @@
+//! Locks on state files. Contenders wait up to a timeout, set by [LOCK_TIMEOUT_VAR] in seconds.
+//! Exclusive holders record who they are next to the lock, so that the error can tell.
+
+use std::{
+    env::{args, var},
+    fs::{read_to_string, remove_file, File},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use fs4::FileExt;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::*;
+use crate::sys::atomic_write;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockMode {
+    /// For inspection. Many readers may hold it at once.
+    Shared,
+    Exclusive,
+}
+
+#[public]
+#[derive(Serialize, Deserialize, Debug)]
+struct Holder {
+    pid: u32,
+    cmd: String,
+    /// Unix time
+    since: u64,
+}
+
+#[derive(Debug)]
+pub struct StateLock {
+    file: File,
+    path: PathBuf,
+    mode: LockMode,
+}
+
+fn timeout() -> Duration {
+    match var(LOCK_TIMEOUT_VAR).ok().and_then(|t| t.parse().ok()) {
+        Some(secs) => Duration::from_secs(secs),
+        None => DEFAULT_TIMEOUT,
+    }
+}
+
+impl Holder {
+    fn describe(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(self.since);
+        format!(
+            "pid {} ({}), since {}s ago",
+            self.pid,
+            self.cmd,
+            now.saturating_sub(self.since)
+        )
+    }
+}
+
+#[public]
+impl StateLock {
+    /// Waits for the lock, creating the lock file if absent
+    fn acquire(path: &Path, mode: LockMode) -> Result<Self> {
+        if !path.exists() {
+            File::create(path)?;
+        }
+        // Readable by all, which suffices for flock
+        let file = File::open(path)?;
+        let timeout = timeout();
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let rx = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+            };
+            if rx.is_ok() {
+                break;
+            }
+            let holder = match Self::holder(path) {
+                Some(h) => h.describe(),
+                None => "a reader".to_owned(),
+            };
+            if start.elapsed() >= timeout {
+                bail!(
+                    "State file locked by {}. Gave up after {:?}, set {} to wait longer",
+                    holder,
+                    timeout,
+                    LOCK_TIMEOUT_VAR
+                );
+            }
+            if !waiting {
+                info!("Waiting for the lock held by {}", holder);
+                waiting = true;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        let lock = Self {
+            file,
+            path: path.to_owned(),
+            mode,
+        };
+        if mode == LockMode::Exclusive {
+            if let Err(e) = lock.record() {
+                warn!("Failed to record the lock holder, {}", e);
+            }
+        }
+        Ok(lock)
+    }
+    fn holder_path(path: &Path) -> PathBuf {
+        path.with_extension("holder")
+    }
+    /// The exclusive holder, if it is alive
+    fn holder(path: &Path) -> Option<Holder> {
+        let st = read_to_string(Self::holder_path(path)).ok()?;
+        let h: Holder = serde_json::from_str(&st).ok()?;
+        Path::new(&format!("/proc/{}", h.pid)).exists().then_some(h)
+    }
+    fn record(&self) -> Result<()> {
+        let h = Holder {
+            pid: std::process::id(),
+            cmd: args().collect::<Vec<_>>().join(" "),
+            since: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        atomic_write(
+            &Self::holder_path(&self.path),
+            &serde_json::to_vec(&h)?,
+            None,
+        )
+    }
+    fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        if self.mode == LockMode::Exclusive {
+            let _ = remove_file(Self::holder_path(&self.path));
+        }
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// A second taker gives up after the timeout, naming the holder
+#[test]
+fn lock_timeout() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("nsproxy-lock-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("graphs.lock");
+    std::env::set_var(LOCK_TIMEOUT_VAR, "1");
+    let held = StateLock::acquire(&path, LockMode::Exclusive)?;
+    let start = Instant::now();
+    let err = StateLock::acquire(&path, LockMode::Shared)
+        .unwrap_err()
+        .to_string();
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(
+        err.contains(&format!("pid {}", std::process::id())),
+        "{}",
+        err
+    );
+    drop(held);
+    StateLock::acquire(&path, LockMode::Exclusive)?;
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
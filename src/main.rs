@@ -4,7 +4,7 @@
 #![feature(array_try_map)]
 #![feature(ip_bits)]
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env::{current_exe, var};
 use std::fmt::format;
 use std::fs::{OpenOptions, Permissions};
@@ -33,10 +33,12 @@ use fork::Fork;
 use futures::{FutureExt, SinkExt};
 use id_alloc::NetRange;
 use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
-use libc::{uid_t, SIGTERM};
+use libc::{uid_t, SIGKILL, SIGTERM};
 use log::LevelFilter::{self, Debug};
 use log::{debug, error};
-use netlink_ops::netlink::{nl_ctx, GetPidOrFd, NLDriver, NLHandle, PidOrFd, VPairKey, VethConn};
+use netlink_ops::netlink::{
+    nl_ctx, GetPidOrFd, LinkAB, NLDriver, NLHandle, PidOrFd, VPairKey, VethConn,
+};
 use netlink_ops::rtnetlink::netlink_packet_utils::byteorder::{
     BigEndian, ReadBytesExt, WriteBytesExt,
 };
@@ -45,30 +47,42 @@ use netlink_ops::rtnetlink::netlink_sys::protocols::NETLINK_ROUTE;
 use netlink_ops::rtnetlink::netlink_sys::{Socket, TokioSocket};
 use netlink_ops::rtnetlink::Handle;
 use netlink_ops::state::{Existence, ExpCollection};
+use nix::errno::Errno;
 use nix::fcntl::{open, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sched::{setns, unshare, CloneFlags};
-use nix::sys::signal::{signal, SigHandler};
+use nix::sys::signal::{kill, signal, SigHandler, Signal};
 use nix::sys::stat::Mode;
 use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::{
-    close, fork, geteuid, getgid, getpid, getppid, getuid, sethostname, setresuid, setsid,
-    ForkResult, Pid, Uid,
+    close, fork, geteuid, getgid, getpid, getppid, getuid, setresuid, setsid, ForkResult, Pid, Uid,
 };
 use nsproxy::data::{
-    FDRecver, Graphs, NSAdd, NSAddRes, NSGroup, NSSlot, NSState, NodeAddr, NodeI, ObjectNode,
-    PassFD, Relation, Validate, ValidateR, TUNC,
+    FDRecver, Graphs, NSAdd, NSAddRes, NSGroup, NSSlot, NSState, NSTime, NSTrait, NodeAddr, NodeI,
+    ObjectNode, PassFD, Relation, Route, SocketC, Validate, ValidateR, TUNC,
 };
+use nsproxy::export::{edge_label, export, ExportFormat};
 use nsproxy::flatpak::FlatpakID;
-use nsproxy::graph::{check_veths, FResult};
+use nsproxy::graph::{check_veths, netns_inventory, FResult, RM};
+use nsproxy::identity::{Identity, MACHINE_ID};
+use nsproxy::keeper::{self, HeldNS};
 use nsproxy::managed::{
     Indexed, ItemAction, ItemCreate, NodeIDPrint, NodeIndexed, NodeWDeps, ServiceM, Socks2TUN,
+    UnitRecver,
 };
 use nsproxy::paths::{PathState, Paths};
+use nsproxy::plan::{Action, Planned, Recorder};
+use nsproxy::recover::found_veths;
+use nsproxy::report::{
+    CheckReport, DepsReport, GraphReport, NetNSListReport, OutputFormat, PsReport, Report,
+};
 use nsproxy::sys::{
-    check_capsys, cmd_uid, connect_ns_veth, enable_ping_all, enable_ping_gid, systemd_connection,
-    unshare_user_standalone, what_uid, your_shell, UserNS,
+    alloc_veth, check_capsys, cmd_uid, cmdline, connect_ns_veth, enable_ping_all, enable_ping_gid,
+    nl_in_ns, systemd_connection, unshare_user_standalone, veth_action, what_uid, your_shell,
+    LinkM, UserNS,
 };
 use nsproxy::systemd::{match_root, UnitName};
+use nsproxy::topology::{Step, Topology};
 use nsproxy::watcher::FlatpakWatcher;
 use nsproxy::*;
 use nsproxy::{data::Ix, systemd};
@@ -89,6 +103,7 @@ use tokio::time::sleep;
 use tracing::instrument::WithSubscriber;
 use tracing::{info, warn, Level};
 use tracing_log::LogTracer;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::FmtSubscriber;
 use tun::{AsyncDevice, Configuration, Device, Layer};
 use tun2socks5::IArgs;
@@ -105,6 +120,9 @@ struct Cli {
     log: Option<Level>,
     #[command(subcommand)]
     command: Commands,
+    /// Format of reports printed by info, node and log
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    output: OutputFormat,
     /// Require repeated signals to exit
     #[arg(long, short, default_value = "1")]
     sigint: u8,
@@ -118,6 +136,12 @@ enum Commands {
         /// Config file for Tun2proxy
         #[arg(long, short)]
         tun2proxy: Option<PathBuf>,
+        /// Service to receive the TUN, activated through a socket unit
+        #[arg(long, conflicts_with = "tun2proxy")]
+        tun_to_unit: Option<String>,
+        /// Unix socket a running receiver listens on for the TUN
+        #[arg(long, conflicts_with_all = ["tun2proxy", "tun_to_unit"])]
+        tun_to_socket: Option<PathBuf>,
         /// Command to run
         cmd: Option<String>,
         #[arg(long, short)]
@@ -140,6 +164,24 @@ enum Commands {
         associated: Option<String>,
         #[arg(long, default_value = "192.168.2.1/24")]
         assoc_ip: Option<IpNetwork>,
+        /// Give the node its own IPC NS, so that shared memory and SysV IPC are not shared with apps outside
+        #[arg(long)]
+        ipc: bool,
+        /// Give the node its own cgroup NS
+        #[arg(long)]
+        cgroup: bool,
+        /// Give the node its own time NS, whose clocks start from zero
+        #[arg(long)]
+        time: bool,
+        /// Give the node its own PID NS, so its processes can be listed and stopped together
+        #[arg(long)]
+        pidns: bool,
+        /// Hostname of the node, or `random`
+        #[arg(long)]
+        hostname: Option<String>,
+        /// Give the node a random machine-id
+        #[arg(long)]
+        machine_id: bool,
     },
     /// Start as watcher daemon. This uses the socks2tun method.
     Watch {
@@ -148,10 +190,49 @@ enum Commands {
         #[arg(long, short)]
         dryrun: bool,
     },
+    /// Create, update or remove named nodes until they match a topology file
+    Apply {
+        path: PathBuf,
+        /// Print the steps without performing them
+        #[arg(long, short)]
+        dryrun: bool,
+        /// Seconds to wait for each node to be recorded
+        #[arg(long, default_value = "60")]
+        timeout: u64,
+    },
+    /// Remove nodes that are gone, with their veths and units
+    Gc {
+        /// Keep running, and collect when processes of nodes exit
+        #[arg(long, short)]
+        watch: bool,
+        /// List what would be removed and why
+        #[arg(long)]
+        dry_run: bool,
+        /// Seconds between collections when watching
+        #[arg(long, default_value = "60")]
+        interval: u64,
+    },
+    /// Rebuild the state file from mounted NSes, veths and units, when it is lost
+    Recover {
+        /// Replace the state file even if it records nodes, or is broken
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the side effects of a command in order, without performing them.
+    /// Only `new` is supported.
+    Plan {
+        #[command(subcommand)]
+        command: Box<Commands>,
+    },
     /// Run probe process acccording to the graph. ID for Node ID
     Probe {
         id: Ix,
     },
+    /// Hold the NSes of a process for the node, until it exits
+    Keep {
+        id: Ix,
+        pid: pid_t,
+    },
     /// Preset for a container having a local interface
     Local {
         interface: String,
@@ -176,6 +257,36 @@ enum Commands {
         setup: bool,
     },
     Info,
+    /// Check the environment and print hints for what is missing
+    Doctor,
+    /// Show the audit log of nodes, edges, units and links
+    Log {
+        /// Only entries concerning the node
+        #[arg(value_parser=parse_node)]
+        node: Option<NodeAddr>,
+        /// Number of latest entries to show
+        #[arg(long, short, default_value = "20")]
+        n: usize,
+    },
+    /// Namespaces of the system
+    Ns {
+        #[command(subcommand)]
+        op: NsOps,
+    },
+    /// Backups of the state file
+    State {
+        #[command(subcommand)]
+        op: StateOps,
+    },
+    Graph {
+        #[command(subcommand)]
+        op: GraphOps,
+    },
+    /// Relations of existing nodes. Processes in the nodes keep running.
+    Edge {
+        #[command(subcommand)]
+        op: EdgeOps,
+    },
     /// Enter the initialized user&mnt ns
     Userns {
         #[arg(long, short)]
@@ -189,6 +300,9 @@ enum Commands {
         deinit: bool,
         #[arg(long, short)]
         exit: bool,
+        /// Hold the NSes in a process instead of mounting them, which needs no root
+        #[arg(long)]
+        keeper: bool,
     },
     Node {
         #[arg(value_parser=parse_node, default_value="0")]
@@ -256,6 +370,68 @@ fn parse_node(addr: &str) -> Result<NodeAddr> {
     }
 }
 
+#[derive(Subcommand)]
+enum StateOps {
+    /// List the generations kept. 1 is the latest
+    Ls,
+    /// Roll back to a generation
+    Restore { generation: usize },
+}
+
+#[derive(Subcommand)]
+enum NsOps {
+    /// Every NetNS held by a process, with the node it maps to, or as foreign
+    Ls,
+}
+
+#[derive(Subcommand)]
+enum GraphOps {
+    /// Print the graph for rendering
+    Export {
+        #[arg(long, short, value_enum, default_value = "dot")]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum EdgeOps {
+    /// Add a relation from the node to --to, or to the NS you are in
+    Add {
+        #[arg(value_parser=parse_node)]
+        node: NodeAddr,
+        /// Config file for Tun2proxy
+        #[arg(long, short)]
+        tun2proxy: Option<PathBuf>,
+        /// Service to receive the TUN, activated through a socket unit
+        #[arg(long, conflicts_with = "tun2proxy")]
+        tun_to_unit: Option<String>,
+        /// Unix socket a running receiver listens on for the TUN
+        #[arg(long, conflicts_with_all = ["tun2proxy", "tun_to_unit"])]
+        tun_to_socket: Option<PathBuf>,
+        /// Link the nodes with a veth. Implied when only --to is given
+        #[arg(long, short)]
+        veth: bool,
+        #[arg(long, value_parser=parse_node)]
+        to: Option<NodeAddr>,
+        /// Listen on the address in the node, ex. 127.0.0.1:1080, and pass the socket
+        #[arg(long, short)]
+        socket: Option<String>,
+        /// Service to receive the socket, activated through a socket unit
+        #[arg(long, requires = "socket")]
+        unit: Option<String>,
+        /// Unix socket a running receiver listens on, instead of --unit
+        #[arg(long, requires = "socket", conflicts_with = "unit")]
+        listener: Option<PathBuf>,
+    },
+    /// Remove the relation, its units and links
+    Rm { edge: Ix },
+    /// List relations, of the node if specified
+    Ls {
+        #[arg(value_parser=parse_node)]
+        node: Option<NodeAddr>,
+    },
+}
+
 #[derive(Subcommand)]
 enum NodeOps {
     Deps {
@@ -271,9 +447,20 @@ enum NodeOps {
         uid: Option<u32>,
     },
     Reboot,
+    /// Remove the nodes, stopping their processes
     RM {
         ids: Vec<Ix>,
     },
+    /// List the processes of the node
+    Ps,
+    /// Validate the NSes of the node without entering them. Exits with 1 if any does not match
+    Check,
+    /// Stop the processes of the node, the whole PID NS if it has one
+    Stop {
+        /// Seconds before they are killed
+        #[arg(long, short, default_value = "5")]
+        timeout: u64,
+    },
     /// return moved interfaces
     Restore {
         #[arg(long, short)]
@@ -366,14 +553,20 @@ fn main() -> Result<()> {
         _ => (),
     }
 
+    let writer = match cli.output {
+        OutputFormat::Text => BoxMakeWriter::new(std::io::stdout),
+        // Keep stdout parseable
+        OutputFormat::Json => BoxMakeWriter::new(std::io::stderr),
+    };
     let subscriber = FmtSubscriber::builder()
         .compact()
         .without_time()
+        .with_writer(writer)
         .with_max_level(cli.log.unwrap_or(Level::INFO))
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
     LogTracer::init()?;
-    info!("SHA1: {}", env!("VERGEN_GIT_SHA"));
+    info!("SHA1: {}", GIT_SHA);
     let cwd = std::env::current_dir()?;
 
     // set limits
@@ -405,6 +598,16 @@ fn cmd(
     cli: Cli,
     cwd: PathBuf,
     cb: Option<Box<dyn FnOnce() -> Result<()>>>,
+) -> Result<(), anyhow::Error> {
+    cmd_planned(cli, cwd, cb, None)
+}
+
+/// plan: Set by `plan`, for the stand-ins of `new` to record into
+fn cmd_planned(
+    cli: Cli,
+    cwd: PathBuf,
+    cb: Option<Box<dyn FnOnce() -> Result<()>>>,
+    plan: Option<Recorder>,
 ) -> Result<(), anyhow::Error> {
     let mut nonecb = Some(Box::new(|| aok!()) as Box<dyn FnOnce() -> Result<()>>);
     nonecb.take();
@@ -477,6 +680,7 @@ fn cmd(
         Commands::Enter { name, cmd: op, uid } => {
             let cl = Cli {
                 log: cli.log,
+                output: cli.output,
                 command: Commands::Node {
                     id: Some(NodeAddr::Name(name.resolve().to_owned())),
                     op: NodeOps::Run { cmd: op, uid }.into(),
@@ -504,10 +708,13 @@ fn cmd(
                     cmd(
                         Cli {
                             log: cli.log,
+                            output: cli.output,
                             sigint: 1,
                             command: Commands::New {
                                 pid: None,
                                 tun2proxy: None,
+                                tun_to_unit: None,
+                                tun_to_socket: None,
                                 cmd: None,
                                 uid: None,
                                 name: node_name.to_owned().into(),
@@ -522,6 +729,12 @@ fn cmd(
                                 } else {
                                     "192.168.2.2/24".parse()?
                                 }),
+                                ipc: false,
+                                cgroup: false,
+                                time: false,
+                                pidns: false,
+                                hostname: None,
+                                machine_id: false,
                             },
                         },
                         cwd.clone(),
@@ -530,6 +743,7 @@ fn cmd(
                             cmd(
                                 Cli {
                                     log: cli.log,
+                                    output: cli.output,
                                     sigint: 1,
                                     command: Commands::TUN2proxy {
                                         cmd: TUN2ProxyCmd::FromArgs {
@@ -566,6 +780,7 @@ fn cmd(
                     cmd(
                         Cli {
                             log: cli.log,
+                            output: cli.output,
                             command: Commands::Node {
                                 id: None,
                                 op: NodeOps::RM {
@@ -584,6 +799,8 @@ fn cmd(
         Commands::New {
             pid,
             mut tun2proxy,
+            tun_to_unit,
+            tun_to_socket,
             cmd,
             uid,
             name,
@@ -594,6 +811,12 @@ fn cmd(
             set_dns,
             associated,
             assoc_ip,
+            ipc,
+            cgroup,
+            time,
+            pidns,
+            hostname,
+            machine_id,
         } => {
             let current_uid = what_uid(None, true)?;
 
@@ -601,29 +824,37 @@ fn cmd(
             let paths: Paths = paths.into();
 
             let mut graphs = Graphs::load_file(&paths)?;
+            graphs.plan = plan.clone();
+            let planning = plan.is_some();
             let target_uid = what_uid(uid, true)?;
 
             if let Some(ref mut tun2proxy) = tun2proxy {
                 *tun2proxy = tun2proxy.canonicalize()?;
             }
-            // Connect and authenticate to systemd before entering userns
+            let tun_to = tun_recver(tun_to_unit, tun_to_socket.map(|p| cwd.join(p)));
+            let passes_tun = tun_to.is_some();
+            let identity = Identity::new(hostname, machine_id);
+            if pid.is_some() && !identity.is_empty() {
+                bail!("--hostname and --machine-id only apply to new processes");
+            }
             let rootful = geteuid().is_root();
-            let pre = block_on(async { systemd_connection(rootful).await })??;
+            let mut serv = if let Some(rec) = &plan {
+                systemd::Systemd::planned(&paths, rootful, rec.clone())?
+            } else {
+                // Connect and authenticate to systemd before entering userns
+                let pre = block_on(async { systemd_connection(rootful).await })??;
+                systemd::Systemd::new(&paths, Some(pre), rootful)?
+            };
             let priv_ns;
             let mut va = VaCache::default();
-            let mut serv = systemd::Systemd::new(&paths, Some(pre), rootful)?;
-            let mut rmnode = Default::default();
 
-            block_on(async {
-                let mut nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
-                let ctx = NSGroup::proc_path(Selfproc, None)?;
-                nl.fill().await?;
-                graphs
-                    .prune(&ctx, &mut va, &mut serv, &mut rmnode, &mut nl)
-                    .await?;
-                graphs.do_prune(&ctx, &serv, rmnode, &mut nl).await?;
-                aok!()
-            })??;
+            block_on(prune_with(
+                &mut graphs,
+                &paths,
+                &mut va,
+                &mut serv,
+                plan.as_ref(),
+            ))??;
 
             let gid_out = getgid();
             let mut depriv_userns = false;
@@ -638,16 +869,22 @@ fn cmd(
                     // It only makes sense when we have a persistent userns to mount
                     if !paths.userns().exist()? {
                         println!(
-                            "User NS does not exist. Create it as root with command {}",
-                            "sproxy userns".bright_yellow()
+                            "User NS does not exist. Create it as root with command {}, or without root by {}",
+                            "sproxy userns".bright_yellow(),
+                            "nsproxy userns --keeper".bright_yellow()
                         );
                         exit(-1);
                     }
                     priv_ns = Some(paths.userns().procns()?);
 
-                    let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
-                    priv_ns.as_ref().unwrap().enter(&ctx)?;
-                    log::info!("Entered user, mnt NS");
+                    if !planning {
+                        let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+                        priv_ns.as_ref().unwrap().enter(&ctx)?;
+                        log::info!("Entered user, mnt NS");
+                    }
+                } else if planning {
+                    // The new userns is unshared along with the others
+                    priv_ns = None;
                 } else {
                     // Not mounting defaults to use a new userns
                     priv_ns = Some(unshare_user_standalone(
@@ -660,7 +897,9 @@ fn cmd(
                     depriv_userns = true;
                 }
 
-                check_capsys()?;
+                if !planning {
+                    check_capsys()?;
+                }
             } else {
                 let eu = geteuid();
                 // The user is using SUID or sudo, or we are alredy in a userns, or user did setcap.
@@ -671,38 +910,85 @@ fn cmd(
                 )?);
             }
             if set_dns {
-                etc_resolv::mount_conf()?;
+                if let Some(rec) = &plan {
+                    for act in etc_resolv::conf_actions() {
+                        rec.push(act);
+                    }
+                } else {
+                    etc_resolv::mount_conf()?;
+                }
             }
             let ns_add = if mount {
                 NSAdd::RecordMountedPaths
             } else {
                 NSAdd::RecordProcfsPaths
             };
-            let mut rmnode = Default::default();
-            block_on(async {
-                let mut nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
-                let ctx = NSGroup::proc_path(Selfproc, None)?;
-                nl.fill().await?;
-                graphs
-                    .prune(&ctx, &mut va, &mut serv, &mut rmnode, &mut nl)
-                    .await?;
-                graphs.do_prune(&ctx, &serv, rmnode, &mut nl).await?;
-                aok!()
-            })??;
+            block_on(prune_with(
+                &mut graphs,
+                &paths,
+                &mut va,
+                &mut serv,
+                plan.as_ref(),
+            ))??;
             // Prune is called twice because some NSes are visible only in userns
             let (mut sp, mut sc) = UnixStream::pair()?;
             let mut buf = [0; 1];
             let mut nl_fd = None;
             // NS by Pid --send fd of TUN/socket--> NS of TUN2proxy
             let (src_res, src) = if let Some(pid) = pid {
-                graphs.add_ns(
+                let k = graphs.add_ns(
                     PidPath::N(pid),
                     &paths,
                     priv_ns.as_ref(),
                     ns_add,
                     name,
                     rootful,
-                )?
+                )?;
+                if !planning {
+                    // Entering falls back to the recorded sources without it
+                    if let Err(e) = keeper::spawn(&paths, k.1, pid) {
+                        warn!("{}", e);
+                    }
+                }
+                k
+            } else if let Some(rec) = &plan {
+                // The child is not forked. The node stands in for the NSes it would unshare
+                let opt: Vec<_> = [(ipc, "ipc"), (cgroup, "cgroup"), (time, "time")]
+                    .into_iter()
+                    .filter_map(|(own, kind)| own.then_some(kind))
+                    .collect();
+                let mut unshare = [&["net", "uts"][..], &opt].concat();
+                if pidns {
+                    unshare.push("pid");
+                }
+                if identity.machine_id {
+                    unshare.push("mnt");
+                }
+                let kinds = unshare.clone();
+                if userns && !mount {
+                    unshare.push("user");
+                }
+                rec.push(Action::Spawn {
+                    cmd: cmd.clone(),
+                    unshare: unshare.into_iter().map(String::from).collect(),
+                    hostname: identity.hostname.clone(),
+                });
+                let ix = graphs.add_planned(&kinds, &paths, ns_add, name, rootful)?;
+                if !identity.is_empty() {
+                    for (pa, contents) in identity.files(&paths, ix, target_uid)? {
+                        rec.push(Action::write_file(&pa, &contents));
+                    }
+                    if identity.machine_id {
+                        for target in MACHINE_ID {
+                            rec.push(Action::Mount {
+                                src: Identity::machine_id_path(&paths, ix),
+                                dst: target.into(),
+                            });
+                        }
+                    }
+                    graphs.data[ix].as_mut().unwrap().identity = Some(identity.clone());
+                }
+                (NSAddRes::NewNS, ix)
             } else {
                 match unsafe { fork() }? {
                     ForkResult::Child => {
@@ -710,10 +996,17 @@ fn cmd(
                         drop(sp);
                         prctl::set_pdeathsig(Some(SIGTERM))?;
                         unshare(CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWUTS)?;
+                        if identity.machine_id {
+                            Identity::unshare_mnt()?;
+                        }
+                        unshare_optional(ipc, cgroup, time)?;
+                        if pidns {
+                            // Continues as PID 1 of the new NS
+                            pidns_init(&mut sc)?;
+                        }
+                        // X clients keep working, with the Xauthority prepared by the parent
+                        identity.set_hostname()?;
                         sc.write_all(&[0])?; // #1
-                                             // sethostname("proxied")?;
-                                             // The line above caused XWayland to malfunction for me.
-                                             // Librewolf and vscode launched in nsproxy had intermittent full-system lags.
                         if depriv_userns {
                             enable_ping_gid(gid_in.into())?
                         } else {
@@ -723,6 +1016,10 @@ fn cmd(
                         sc.send_fd(nl.as_raw_fd())?;
                         let currnode = sc.read_i32::<BigEndian>()?; // 2
                         CURR_NODE.store(currnode as i32, SeqCst);
+                        if identity.machine_id {
+                            // Its file is written by now
+                            Identity::bind_machine_id(&paths, NodeI::new(currnode as usize))?;
+                        }
                         if let Some(cb) = cb {
                             sc.read_exact(&mut buf)?; // 3
                             cb()?;
@@ -744,23 +1041,36 @@ fn cmd(
                             // We don't change uid of this process.
                             // Otherwise probe might fail due to perms
                             cmd.current_dir(cwd);
-                            cmd_uid(Some(target_uid), true, false)?;
-                            cmd.uid(target_uid);
                             cmd.env(PATH_VAR, &pspath);
 
                             sc.read_exact(&mut buf)?; // 3
+                            if !identity.is_empty() {
+                                let ix = NodeI::new(currnode as usize);
+                                Identity::apply(&paths, ix, &mut cmd)?;
+                            }
+                            cmd_uid(Some(target_uid), true, false)?;
+                            cmd.uid(target_uid);
                             let mut ch = cmd.spawn()?;
-                            ch.wait()?;
+                            if pidns {
+                                pidns_reap()?;
+                            } else {
+                                ch.wait()?;
+                            }
                         }
 
                         exit(0);
                     }
                     ForkResult::Parent { child } => {
                         drop(sc);
+                        let child = if pidns {
+                            sp.read_i32::<BigEndian>()? // 0
+                        } else {
+                            child.as_raw()
+                        };
                         sp.read_exact(&mut buf)?; // 1
                         nl_fd = Some(sp.recv_fd()?);
                         let k = graphs.add_ns(
-                            PidPath::N(child.as_raw()),
+                            PidPath::N(child),
                             &paths,
                             priv_ns.as_ref(),
                             // We have no privs to mount with when new_userns==true
@@ -769,6 +1079,14 @@ fn cmd(
                             rootful,
                         )?;
                         CURR_NODE.store(k.1.index() as i32, SeqCst);
+                        // Entering falls back to the recorded sources without it
+                        if let Err(e) = keeper::spawn(&paths, k.1, child) {
+                            warn!("{}", e);
+                        }
+                        if !identity.is_empty() {
+                            identity.prepare(&paths, k.1, target_uid)?;
+                            graphs.data[k.1].as_mut().unwrap().identity = Some(identity.clone());
+                        }
                         sp.write_i32::<BigEndian>(k.1.index() as i32)?; // 2
                         k
                     }
@@ -800,64 +1118,95 @@ fn cmd(
                 };
 
                 if let Some(ref tun2proxy) = tun2proxy {
-                    let edge = graphs.data.add_edge(src, out, None);
-                    log::info!(
-                        "Src/Probe {src:?} {}, OutNode(This process), Src --TUN--> Out {edge:?}",
-                        graphs.data[src].as_ref().unwrap().main.key()
-                    );
-                    let socks2t = Socks2TUN::new(&tun2proxy, edge)?;
-                    let rel = socks2t
-                        .write((Layer::L3, Some(pspath.clone())), &serv)
+                    graphs
+                        .add_tun2proxy(src, out, tun2proxy, Some(pspath.clone()), &serv)
+                        .await?;
+                }
+                if let Some((recver, listener)) = tun_to {
+                    graphs
+                        .add_tun_recver(src, out, recver, listener, &serv)
                         .await?;
-                    graphs.data[edge].replace(rel);
                 }
                 let root = NLHandle::new_self_proc_tokio()?;
 
-                if let Some(nl_fd) = nl_fd {
+                // Netlink of the child, which is not forked when planning
+                let sub = if let Some(nl_fd) = nl_fd {
                     let (nl_ch_conn, handle_ch, _) =
                         new_connection_from_socket::<_, _, NetlinkCodec>(unsafe {
                             TokioSocket::from_raw_fd(nl_fd)
                         });
                     tokio::spawn(nl_ch_conn);
 
-                    let sub = NLHandle::new(Handle::new(handle_ch), chid.clone());
+                    Some(NLHandle::new(Handle::new(handle_ch), chid.clone()))
+                } else {
+                    None
+                };
 
+                if pid.is_none() {
                     if let Some(interface) = associated {
-                        let link = root.get_link(interface.parse()?).await?;
-                        let id = link.header.index;
-                        let fd = chid.open()?;
-                        info!("moving {} into the new netns", interface);
-                        root.ip_setns(&fd, id).await?;
-                        if let Some(ip) = assoc_ip {
-                            let renamed = format!("{}_", interface);
-                            let link = sub.get_link(interface.parse()?).await?;
+                        let act = Action::MoveLink {
+                            name: interface.clone(),
+                            to: chid.unique,
+                            rename: format!("{}_", interface),
+                            addr: assoc_ip,
+                        };
+                        if !graphs.record(&act) {
+                            let sub = sub.as_ref().unwrap();
+                            let link = root.get_link(interface.parse()?).await?;
                             let id = link.header.index;
-                            sub.rawh
-                                .link()
-                                .set(id)
-                                .name(renamed.clone())
-                                .execute()
-                                .await?;
-                            info!("add ip to moved interface");
-                            sub.add_addr_dev(ip, id).await?;
-                            sub.set_link_up(id).await?;
-                        } else {
-                            warn!("no ip supplied");
+                            let fd = chid.open()?;
+                            info!("moving {} into the new netns", interface);
+                            root.ip_setns(&fd, id).await?;
+                            if let Some(ip) = assoc_ip {
+                                let renamed = format!("{}_", interface);
+                                let link = sub.get_link(interface.parse()?).await?;
+                                let id = link.header.index;
+                                sub.rawh
+                                    .link()
+                                    .set(id)
+                                    .name(renamed.clone())
+                                    .execute()
+                                    .await?;
+                                info!("add ip to moved interface");
+                                sub.add_addr_dev(ip, id).await?;
+                                sub.set_link_up(id).await?;
+                            } else {
+                                warn!("no ip supplied");
+                            }
+                            graphs.done(&act);
                         }
                     }
 
                     if veth {
                         let veth_key: Option<VPairKey>;
                         veth_key = Some(format!("v{}to{}", src.index(), out.index()).try_into()?);
-                        let vc = connect_ns_veth(sub, root.clone(), veth_key).await?;
+                        graphs.route_edge(src, out, Route::Link)?;
+                        let vc = if let Some(sub) = sub {
+                            connect_ns_veth(sub, root.clone(), veth_key).await?
+                        } else {
+                            // Addresses are picked as they would be, and nothing is changed
+                            let mut nl = NLDriver::new(root.clone());
+                            let vc = alloc_veth(&mut nl, veth_key).await?;
+                            graphs.record(&Action::LinkUp("lo".to_owned()));
+                            graphs.record(&veth_action(&vc));
+                            vc
+                        };
                         let edge = graphs.data.add_edge(src, out, None);
+                        let act = Action::AddEdge {
+                            ix: edge,
+                            src,
+                            dst: out,
+                            proxy: None,
+                        };
+                        graphs.record(&act);
                         graphs.data[edge].replace(Relation::Veth(vc));
+                        graphs.done(&act);
                     }
                 }
 
                 let ctx = serv.ctx().await?;
                 graphs.dump_file(&paths, target_uid)?;
-                if tun2proxy.is_some() {
+                if tun2proxy.is_some() || passes_tun {
                     let nw = graphs.nodewdeps(src)?;
                     nw.write(Some(pspath.clone()), &serv).await?;
                     serv.reload(&ctx).await?;
@@ -867,25 +1216,66 @@ fn cmd(
 
                 aok!()
             })??;
-            sp.write_all(&[2])?; // 3
+            if !planning {
+                sp.write_all(&[2])?; // 3
 
-            // Wait for the child, or it gets orphaned.
-            waitpid(Some(Pid::from_raw(-1)), None)?;
+                // Wait for the child, or it gets orphaned.
+                waitpid(Some(Pid::from_raw(-1)), None)?;
+            }
+        }
+        Commands::Plan { command } => {
+            let rec = Recorder::default();
+            if !matches!(*command, Commands::New { .. }) {
+                bail!("Only nsproxy new can be planned");
+            }
+            cmd_planned(
+                Cli {
+                    log: cli.log,
+                    output: cli.output,
+                    command: *command,
+                    sigint: cli.sigint,
+                },
+                cwd,
+                None,
+                Some(rec.clone()),
+            )?;
+            for action in rec.take() {
+                println!("{}", action);
+            }
+        }
+        Commands::Keep { id, pid } => {
+            let (_, paths): (PathBuf, PathState) = PathState::load(what_uid(None, true)?)?;
+            keeper::keep(&paths, NodeI::from(id), pid)?;
         }
         Commands::Probe { id } => {
             let (pspath, paths): (PathBuf, PathState) = PathState::load(what_uid(None, true)?)?;
             let paths: Paths = paths.into();
 
-            let graphs = Graphs::load_file(&paths)?;
+            let graphs = Graphs::load_file_ro(&paths)?;
             // Load graphs, send FDs over socket
             let (node, deps) = graphs.nodewdeps(NodeI::from(id))?;
             let mut va = VaCache::default();
             let mut nss = NSState {
                 target: &node.item.main,
                 va: &mut va,
+                held: HeldNS::connect(&paths, NodeI::from(id)),
             };
             log::info!("{:?}", &node.item.main);
             nss.validated_enter()?;
+            let has = |f: fn(&Relation) -> bool| deps.iter().any(|r| f(r.edge.item));
+            let (tun, socket) = (
+                has(|r| matches!(r, Relation::SendTUN(_))),
+                has(|r| matches!(r, Relation::SendSocket(_))),
+            );
+            if socket {
+                // Sockets may be bound on loopback
+                block_on(async {
+                    let wh = NLDriver::new(NLHandle::new_self_proc_tokio()?);
+                    let li = wh.conn.get_link("lo".parse()?).await?;
+                    wh.conn.set_link_up(li.header.index).await?;
+                    aok!()
+                })??;
+            }
 
             for rel in deps {
                 match rel.edge.item {
@@ -894,6 +1284,10 @@ fn cmd(
                     _ => (),
                 }
             }
+            drop(graphs);
+            if !tun {
+                return Ok(());
+            }
             block_on(async {
                 let wh = NLDriver::new(NLHandle::new_self_proc_tokio()?);
                 // let mut nl = NLStateful::new(&wh);
@@ -924,6 +1318,9 @@ fn cmd(
                 TUN2ProxyCmd::Systemd { path, id } => true,
             };
             let default_tun_name = "tun0";
+            if let TUN2ProxyCmd::Systemd { id: Some(id), .. } = &cmd {
+                enter_out_net(&paths, EdgeI::from(*id as u32))?;
+            }
 
             let dev = if getfd_systemd {
                 // Setns, recv FD, start daemon
@@ -933,11 +1330,10 @@ fn cmd(
                 let fdx = fds.pop().unwrap();
                 let fdx = unsafe { UnixListener::from_raw_fd(fdx.into_raw_fd()) };
                 log::info!("Waiting for device FD");
-                let (conn, _addr) = fdx.accept()?;
-                let devfd = conn.recv_fd()?;
+                let devfd = fdrecv::accept(&fdx)?;
                 log::info!("Got FD");
                 let devconf = Default::default();
-                let dev = tun::platform::linux::Device::from_raw_fd(devfd.as_raw_fd(), &devconf)?;
+                let dev = tun::platform::linux::Device::from_raw_fd(devfd.into_raw_fd(), &devconf)?;
                 dev
             } else {
                 let args = match &cmd {
@@ -1147,16 +1543,9 @@ fn cmd(
                             // TODO: skip if everything is up
                             continue;
                         } else {
-                            let edge = graphs.data.add_edge(src, out, None);
-                            log::info!(
-                                "Src/Probe {src:?} {}, OutNode(This process), Src -> Out {edge:?}",
-                                graphs.data[src].as_ref().unwrap().main.key()
-                            );
-                            let socks2t = Socks2TUN::new(&path, edge)?;
-                            let rel = socks2t
-                                .write((Layer::L3, Some(pspath.clone())), &serv)
+                            graphs
+                                .add_tun2proxy(src, out, &path, Some(pspath.clone()), &serv)
                                 .await?;
-                            graphs.data[edge].replace(rel);
                             graphs.dump_file(&paths, uid)?;
                         }
                         let nw = graphs.nodewdeps(src)?;
@@ -1174,6 +1563,264 @@ fn cmd(
                 aok!()
             })??;
         }
+        Commands::Gc {
+            watch,
+            dry_run,
+            interval,
+        } => {
+            let uid = what_uid(None, true)?;
+            let (_, paths): (PathBuf, PathState) = PathState::load(uid)?;
+            let paths: Paths = paths.into();
+            let rootful = geteuid().is_root();
+            let pre = block_on(async { systemd_connection(rootful).await })??;
+            let serv = systemd::Systemd::new(&paths, Some(pre), rootful)?;
+            loop {
+                let holders = gc(&paths, &serv, uid, dry_run)?;
+                if !watch {
+                    break;
+                }
+                wait_exit(&holders, Duration::from_secs(interval))?;
+            }
+        }
+        Commands::Recover { force } => {
+            let uid = what_uid(None, true)?;
+            let (_, paths): (PathBuf, PathState) = PathState::load(uid)?;
+            let paths: Paths = paths.into();
+            let rootful = geteuid().is_root();
+            let serv = systemd::Systemd::new(&paths, None, rootful)?;
+            let mut graphs = if force {
+                Graphs::load_blank(&paths)?
+            } else {
+                Graphs::load_file(&paths)?
+            };
+            if graphs.data.node_count() > 0 {
+                bail!(
+                    "The state file records {} nodes. Pass --force to replace it",
+                    graphs.data.node_count()
+                );
+            }
+            let ctx = NSGroup::proc_path(Selfproc, None)?;
+            let mut host = NSGroup::proc_path(Selfproc, Some(NSSource::Unavail(false)))?;
+            host.drop_shared(&ctx);
+            let (tuns, recvers) = serv.found_units()?;
+            // Nodes hold the user and mnt NS they were mounted in, as in new
+            let usermnt = if rootful {
+                NSGroup::proc_path(Selfproc, Some(NSSource::Unavail(false)))?
+            } else {
+                let usern = paths.userns();
+                if !usern.exist()? {
+                    bail!("User NS does not exist, so nothing is mounted to recover from");
+                }
+                let usermnt = usern.procns()?;
+                usermnt.enter(&ctx)?;
+                usermnt
+            };
+            let mut mounted = NSGroup::mounted(&paths, rootful)?;
+            for g in mounted.values_mut() {
+                *g += &usermnt;
+            }
+            // Each veth is named in the NetNS of its out node
+            let veths = block_on(async {
+                let mut nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
+                nl.fill().await?;
+                let mut veths = found_veths(&nl)?;
+                for (ix, g) in &mounted {
+                    let NSSlot::Provided(net, _) = &g.net else {
+                        continue;
+                    };
+                    let mut nl = match nl_in_ns(net).await {
+                        Ok(h) => NLDriver::new(h),
+                        Err(e) => {
+                            warn!("NetNS of node {} not available, {}", ix, e);
+                            continue;
+                        }
+                    };
+                    nl.fill().await?;
+                    for v in found_veths(&nl)? {
+                        if !veths.iter().any(|w| (w.0, w.1) == (v.0, v.1)) {
+                            veths.push(v);
+                        }
+                    }
+                }
+                Ok::<_, anyhow::Error>(veths)
+            })??;
+            info!(
+                "Found {} mounted nodes, {} tun2proxy relations, {} receivers and {} veths",
+                mounted.len(),
+                tuns.len(),
+                recvers.len(),
+                veths.len()
+            );
+            graphs.rebuild(mounted, host, tuns, recvers, veths, rootful)?;
+            graphs.dump_file(&paths, uid)?;
+            summarize_graph(&graphs, cli.output)?;
+        }
+        Commands::Apply {
+            path,
+            dryrun,
+            timeout,
+        } => {
+            let topo = Topology::load(&path)?;
+            let uid = what_uid(None, true)?;
+            let (pspath, paths): (PathBuf, PathState) = PathState::load(uid)?;
+            let paths: Paths = paths.into();
+            let rootful = geteuid().is_root();
+            let pre = block_on(async { systemd_connection(rootful).await })??;
+            let mut serv = systemd::Systemd::new(&paths, Some(pre), rootful)?;
+            let mut graphs = Graphs::load_file(&paths)?;
+            let mut va = VaCache::default();
+            let mut rmnode = Default::default();
+            let steps = block_on(async {
+                let mut nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
+                let ctx = NSGroup::proc_path(Selfproc, None)?;
+                nl.fill().await?;
+                graphs
+                    .prune(&ctx, &mut va, &mut serv, &mut rmnode, &mut nl)
+                    .await?;
+                if !dryrun {
+                    graphs
+                        .do_prune(&ctx, &paths, &serv, rmnode, &mut nl)
+                        .await?;
+                }
+                topo.diff(&graphs, &ctx, &nl, &serv).await
+            })??;
+            if !dryrun {
+                graphs.dump_file(&paths, uid)?;
+            }
+            // Release the state file for the commands below
+            drop(graphs);
+            if steps.is_empty() {
+                println!("Topology matches.");
+            }
+            for step in &steps {
+                println!("{}", step);
+            }
+            if dryrun {
+                return Ok(());
+            }
+            let exe = current_exe()?;
+            let rm: Vec<String> = steps
+                .iter()
+                .filter_map(|s| match s {
+                    Step::Remove(ix, _) | Step::Recreate(ix, _) => Some(ix.index().to_string()),
+                    _ => None,
+                })
+                .collect();
+            if !rm.is_empty() {
+                let status = Command::new(&exe)
+                    .env(PATH_VAR, &pspath)
+                    .env(UID_HINT_VAR, uid.to_string())
+                    .args(["node", "0", "rm"])
+                    .args(&rm)
+                    .status()?;
+                ensure!(status.success(), "failed to remove nodes {:?}", rm);
+            }
+            for step in steps {
+                match step {
+                    Step::Create(spec) | Step::Recreate(_, spec) => {
+                        info!("creating node {}", spec.name);
+                        let mut ch = spec
+                            .command(&exe)
+                            .env(PATH_VAR, &pspath)
+                            .env(UID_HINT_VAR, uid.to_string())
+                            .spawn()?;
+                        // The launch command may run indefinitely, so wait for the node to be recorded instead.
+                        let mut waited = Duration::ZERO;
+                        let tick = Duration::from_millis(500);
+                        loop {
+                            if let Some(st) = ch.try_wait()? {
+                                ensure!(st.success(), "failed to create node {}", spec.name);
+                            }
+                            // The child holds the lock until it has recorded the node
+                            if Graphs::load_file_ro(&paths)?.name.contains_left(&spec.name) {
+                                break;
+                            }
+                            ensure!(
+                                waited < Duration::from_secs(timeout),
+                                "timed out waiting for node {}",
+                                spec.name
+                            );
+                            std::thread::sleep(tick);
+                            waited += tick;
+                        }
+                        // Marks it as ours to remove once it leaves the topology
+                        let mut graphs = Graphs::load_file(&paths)?;
+                        let ix = graphs.resolve(&NodeAddr::Name(spec.name.clone()))?;
+                        graphs.data[ix].as_mut().unwrap().applied = Some(spec);
+                        graphs.dump_file(&paths, uid)?;
+                    }
+                    Step::TUN2Proxy(ix, spec) => {
+                        let mut graphs = Graphs::load_file(&paths)?;
+                        block_on(async {
+                            let ctx = serv.ctx().await?;
+                            let (_, deps) = graphs.nodewdeps(ix)?;
+                            let old: Vec<_> = deps
+                                .iter()
+                                .filter_map(|rel| match rel.edge.item.fd_recver() {
+                                    Some(FDRecver::TUN2Proxy(p)) => Some((rel.edge.id, p.clone())),
+                                    _ => None,
+                                })
+                                .collect();
+                            for (edge, p) in old {
+                                if let Err(e) = Socks2TUN::new(&p, edge)?.stop(&serv, &ctx).await {
+                                    warn!("failed to stop tun2proxy of {:?}, {}", edge, e);
+                                }
+                                graphs.edge_rm(edge, &serv).await?;
+                            }
+                            if let Some(conf) = &spec.tun2proxy {
+                                let out = match &spec.out {
+                                    Some(o) => graphs.resolve(&NodeAddr::Name(o.clone()))?,
+                                    None => {
+                                        graphs
+                                            .add_ns(
+                                                PidPath::Selfproc,
+                                                &paths,
+                                                None,
+                                                NSAdd::RecordNothing,
+                                                None,
+                                                rootful,
+                                            )?
+                                            .1
+                                    }
+                                };
+                                graphs
+                                    .add_tun2proxy(ix, out, conf, Some(pspath.clone()), &serv)
+                                    .await?;
+                            }
+                            graphs.data[ix].as_mut().unwrap().applied = Some(spec);
+                            graphs.dump_file(&paths, uid)?;
+                            let nw = graphs.nodewdeps(ix)?;
+                            nw.write(Some(pspath.clone()), &serv).await?;
+                            serv.reload(&ctx).await?;
+                            nw.1.restart(&serv, &ctx).await?;
+                            nw.0.restart(&serv, &ctx).await?;
+                            aok!()
+                        })??;
+                    }
+                    Step::WriteUnits(ix) => {
+                        let graphs = Graphs::load_file(&paths)?;
+                        block_on(async {
+                            let ctx = serv.ctx().await?;
+                            let nw = graphs.nodewdeps(ix)?;
+                            for rel in nw.1.iter() {
+                                if let Some(FDRecver::TUN2Proxy(p)) = rel.edge.item.fd_recver() {
+                                    Socks2TUN::new(p, rel.edge.id)?
+                                        .upstream(graphs.upstream(rel.dst.id)?)
+                                        .write((Layer::L3, Some(pspath.clone())), &serv)
+                                        .await?;
+                                }
+                            }
+                            nw.write(Some(pspath.clone()), &serv).await?;
+                            serv.reload(&ctx).await?;
+                            nw.1.restart(&serv, &ctx).await?;
+                            nw.0.restart(&serv, &ctx).await?;
+                            aok!()
+                        })??;
+                    }
+                    Step::Remove(..) => (),
+                }
+            }
+        }
         Commands::SetDNS => {
             etc_resolv::mount_conf()?;
         }
@@ -1183,6 +1830,7 @@ fn cmd(
             exit,
             node,
             deinit,
+            keeper,
         } => {
             let wuid = what_uid(None, false)?;
             let (pspath, paths): (PathBuf, PathState) = PathState::load(wuid)?;
@@ -1211,8 +1859,12 @@ fn cmd(
                 }
             } else {
                 log::warn!("UserNS does not exist");
-                check_capsys()?;
-                usern.init(wuid)?;
+                if keeper {
+                    usern.init_kept(wuid)?;
+                } else {
+                    check_capsys()?;
+                    usern.init(wuid)?;
+                }
             }
         }
         Commands::Node { id, op } => {
@@ -1222,7 +1874,7 @@ fn cmd(
             if let Some(op) = op {
                 match op {
                     NodeOps::Run { cmd, uid } => {
-                        let graphs = Graphs::load_file(&paths)?;
+                        let graphs = Graphs::load_file_ro(&paths)?;
                         let require_id = || {
                             if let Some(id) = id {
                                 graphs.resolve(&id)
@@ -1241,19 +1893,78 @@ fn cmd(
                         let mut nss = NSState {
                             target: &node.main,
                             va: &mut va,
+                            held: HeldNS::connect(&paths, ix),
                         };
                         let cwd = std::env::current_dir()?;
+                        let identity = node.identity.is_some();
                         nss.validated_enter()?;
                         drop(graphs);
-                        cmd_uid(uid, true, true)?;
                         let mut cmd = Command::new(
                             your_shell(cmd, uid)?.ok_or(anyhow!("specify env var SHELL"))?,
                         );
+                        if identity {
+                            Identity::apply(&paths, ix, &mut cmd)?;
+                        }
+                        cmd_uid(uid, true, true)?;
                         cmd.current_dir(cwd);
                         cmd.spawn()?.wait()?;
                     }
+                    NodeOps::Ps => {
+                        let graphs = Graphs::load_file_ro(&paths)?;
+                        let ix = graphs.resolve(
+                            &id.ok_or(anyhow!("Node operation requires a node address"))?,
+                        )?;
+                        let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+                        let report = PsReport::new(&graphs, ix, &ctx)?;
+                        if cli.output == OutputFormat::Json {
+                            report.print()?;
+                        } else {
+                            println!("Processes by {} NS of {}", report.by, graphs.node_label(ix));
+                            for p in &report.processes {
+                                println!("{:>8} {:>6} {}", p.holder.pid, p.holder.uid, p.cmdline);
+                            }
+                        }
+                    }
+                    NodeOps::Check => {
+                        let graphs = Graphs::load_file_ro(&paths)?;
+                        let ix = graphs.resolve(
+                            &id.ok_or(anyhow!("Node operation requires a node address"))?,
+                        )?;
+                        let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+                        let report = CheckReport::new(&graphs, ix, &ctx)?;
+                        if cli.output == OutputFormat::Json {
+                            report.print()?;
+                        } else {
+                            println!("Node {}", graphs.node_label(ix));
+                            for ns in &report.namespaces {
+                                let observed =
+                                    ns.observed.map_or("-".to_owned(), |u| u.to_string());
+                                let outcome = ns
+                                    .validation
+                                    .as_ref()
+                                    .map_or("-".to_owned(), |v| v.to_string());
+                                println!(
+                                    "{:<7} {} {} observed {}, {}",
+                                    ns.kind, ns.unique, ns.source, observed, outcome
+                                );
+                            }
+                        }
+                        if !report.pass {
+                            exit(1);
+                        }
+                    }
+                    NodeOps::Stop { timeout } => {
+                        let graphs = Graphs::load_file_ro(&paths)?;
+                        let ix = graphs.resolve(
+                            &id.ok_or(anyhow!("Node operation requires a node address"))?,
+                        )?;
+                        let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+                        let (_, pids) = graphs.processes(ix, &ctx)?;
+                        drop(graphs);
+                        stop_processes(&pids, Duration::from_secs(timeout))?;
+                    }
                     NodeOps::Deps { lines, index } => {
-                        let graphs = Graphs::load_file(&paths)?;
+                        let graphs = Graphs::load_file_ro(&paths)?;
                         let require_id = || {
                             if let Some(id) = id {
                                 graphs.resolve(&id)
@@ -1264,8 +1975,18 @@ fn cmd(
                         let ix = require_id()?;
                         let mut cmd = Command::new("journalctl");
                         let (node, deps) = graphs.nodewdeps(ix)?;
-                        if deps.len() == 0 {
-                            println!("No dependencies.");
+                        if index.is_none() || deps.is_empty() {
+                            let report = DepsReport::new(&graphs, ix)?;
+                            if cli.output == OutputFormat::Json {
+                                report.print()?;
+                            } else if report.relations.is_empty() {
+                                println!("No dependencies.");
+                            } else {
+                                for (i, rel) in report.relations.iter().enumerate() {
+                                    let to = graphs.node_label(NodeI::from(rel.to));
+                                    println!("{:>3} {} -> {}", i, rel.kind, to);
+                                }
+                            }
                         } else {
                             if let Some(index) = index {
                                 let fdrc = deps[index].edge.item.fd_recver();
@@ -1297,13 +2018,11 @@ fn cmd(
                                 } else {
                                     log::error!("No FD receiver at {}", index)
                                 }
-                            } else {
-                                summarize_graph(&graphs)?;
                             }
                         }
                     }
                     NodeOps::Reboot => {
-                        let graphs = Graphs::load_file(&paths)?;
+                        let graphs = Graphs::load_file_ro(&paths)?;
                         let require_id = || {
                             if let Some(id) = id {
                                 graphs.resolve(&id)
@@ -1330,7 +2049,7 @@ fn cmd(
                         let task = async move {
                             let fd = unsafe { BorrowedFd::borrow_raw(fd) };
                             let ns = PidOrFd::Fd(Box::new(fd));
-                            let graphs = Graphs::load_file(&paths)?;
+                            let graphs = Graphs::load_file_ro(&paths)?;
                             let require_id = || {
                                 if let Some(id) = id {
                                     graphs.resolve(&id)
@@ -1349,6 +2068,7 @@ fn cmd(
                             let mut nss = NSState {
                                 target: &node.main,
                                 va: &mut va,
+                                held: HeldNS::connect(&paths, ix),
                             };
                             nss.validated_enter()?;
                             drop(graphs);
@@ -1403,6 +2123,7 @@ fn cmd(
                                         cmd(
                                             Cli {
                                                 log: None,
+                                                output: cli.output,
                                                 sigint: 1,
                                                 command: Commands::Node {
                                                     id: Some(NodeAddr::Ix(*id)),
@@ -1430,6 +2151,21 @@ fn cmd(
                                 }
                             }
                             let mut graphs = Graphs::load_file(&paths)?;
+                            for id in &ids {
+                                // Members of a NetNS alone may be adopted, as of --pid
+                                let own_pid = graphs.data.node_weight(*id).is_some_and(|n| {
+                                    n.as_ref().is_some_and(|n| !n.main.pid.absent())
+                                });
+                                if !own_pid {
+                                    info!("Processes of {:?} left, as it has no PID NS", id);
+                                    continue;
+                                }
+                                // Nodes that share NSes with us have no processes of their own
+                                match graphs.processes(*id, &ctx) {
+                                    Ok((_, pids)) => stop_processes(&pids, Duration::from_secs(5))?,
+                                    Err(e) => info!("Processes of {:?} left, {}", id, e),
+                                }
+                            }
                             let require_id = || {
                                 if let Some(id) = id {
                                     graphs.resolve(&id)
@@ -1444,7 +2180,9 @@ fn cmd(
                                 graphs
                                     .node_rm(&ctx, &ids[..], &mut va, &mut rmnode, &mut nl)
                                     .await?;
-                                graphs.do_prune(&ctx, &serv, rmnode, &mut nl).await?;
+                                graphs
+                                    .do_prune(&ctx, &paths, &serv, rmnode, &mut nl)
+                                    .await?;
                                 Ok::<_, anyhow::Error>(())
                             })??;
 
@@ -1461,7 +2199,7 @@ fn cmd(
                 match op {
                     Some(op) => unimplemented!(),
                     None => {
-                        let mut graphs = Graphs::load_file(&paths)?;
+                        let mut graphs = Graphs::load_file_ro(&paths)?;
                         let require_id = || {
                             if let Some(id) = id {
                                 graphs.resolve(&id)
@@ -1469,7 +2207,7 @@ fn cmd(
                                 bail!("Node operation requires a node address (name/id)")
                             }
                         };
-                        summarize_graph(&graphs)?;
+                        summarize_graph(&graphs, cli.output)?;
                     }
                 }
             }
@@ -1483,10 +2221,246 @@ fn cmd(
                 paths.userns().paths(),
                 paths.userns().exist()?
             );
-            let graphs = Graphs::load_file(&paths);
+            let graphs = Graphs::load_file_ro(&paths);
             match graphs {
-                Ok(g) => summarize_graph(&g)?,
-                Err(e) => println!("graphs not available, {:?}", e),
+                Ok(g) => summarize_graph(&g, cli.output)?,
+                Err(e) => match cli.output {
+                    OutputFormat::Text => println!("graphs not available, {:?}", e),
+                    OutputFormat::Json => GraphReport::error(&e).print()?,
+                },
+            }
+        }
+        Commands::Doctor => {
+            let uid = what_uid(None, true)?;
+            let (_, paths): (PathBuf, PathState) = PathState::load(uid)?;
+            let diags = block_on(doctor::diagnose(&paths, uid))??;
+            doctor::print_table(&diags);
+            if diags.iter().any(|d| d.status == doctor::Status::Fail) {
+                exit(1);
+            }
+        }
+        Commands::Ns { op } => match op {
+            NsOps::Ls => {
+                let (_, paths): (PathBuf, PathState) = PathState::load(what_uid(None, true)?)?;
+                let graphs = Graphs::load_file_ro(&paths)?;
+                let rootful = geteuid().is_root();
+                if !rootful && paths.userns().exist()? {
+                    // The binds are in the mount NS of it
+                    let ctx = NSGroup::proc_path(Selfproc, None)?;
+                    paths.userns().procns()?.enter(&ctx)?;
+                }
+                let binds = paths.private(rootful).ok();
+                let list = NetNSListReport::new(&graphs, netns_inventory(binds.as_deref())?);
+                if cli.output == OutputFormat::Json {
+                    list.print()?;
+                } else {
+                    for ns in &list.netns {
+                        let owner = match ns.node {
+                            Some(ix) => format!("node {}", graphs.node_label(NodeI::from(ix))),
+                            None => "foreign".to_owned(),
+                        };
+                        let user = ns.info.user.map_or("?".to_owned(), |u| u.to_string());
+                        let ifs = ns.info.interfaces.map_or("?".to_owned(), |n| n.to_string());
+                        println!(
+                            "{}  {}, user {}, {} interfaces",
+                            ns.info.unique, owner, user, ifs
+                        );
+                        for h in &ns.info.holders {
+                            println!("{:>8} {:>6} {}", h.pid, h.uid, h.comm);
+                        }
+                        for m in &ns.info.mounts {
+                            println!("{:>8} {}", "bind", m.display());
+                        }
+                    }
+                }
+            }
+        },
+        Commands::State { op } => {
+            let uid = what_uid(None, true)?;
+            let (_, paths): (PathBuf, PathState) = PathState::load(uid)?;
+            match op {
+                StateOps::Ls => {
+                    for (n, path) in Graphs::generations(&paths) {
+                        let age = std::fs::metadata(&path)?.modified()?.elapsed()?;
+                        let nodes = match Graphs::load(&std::fs::read_to_string(&path)?) {
+                            Ok(g) => format!("{} nodes", g.data.node_count()),
+                            Err(e) => format!("unreadable, {}", e),
+                        };
+                        println!("{}  {}s ago, {}  {:?}", n, age.as_secs(), nodes, path);
+                    }
+                }
+                StateOps::Restore { generation } => {
+                    Graphs::restore(&paths, generation, uid)?;
+                }
+            }
+        }
+        Commands::Log { node, n } => {
+            let (_, paths): (PathBuf, PathState) = PathState::load(what_uid(None, true)?)?;
+            let entries = audit::read(&paths)?;
+            let found: Vec<_> = match &node {
+                Some(addr) => audit::query(&entries, addr),
+                None => entries.iter().collect(),
+            };
+            for en in &found[found.len().saturating_sub(n)..] {
+                match cli.output {
+                    OutputFormat::Text => println!("{}", en),
+                    OutputFormat::Json => println!("{}", serde_json::to_string(en)?),
+                }
+            }
+        }
+        Commands::Graph { op } => {
+            let (_, paths): (PathBuf, PathState) = PathState::load(what_uid(None, true)?)?;
+            let paths: Paths = paths.into();
+            let graphs = Graphs::load_file_ro(&paths)?;
+            match op {
+                GraphOps::Export { format } => print!("{}", export(&graphs, format)?),
+            }
+        }
+        Commands::Edge { op } => {
+            let uid = what_uid(None, true)?;
+            let (pspath, paths): (PathBuf, PathState) = PathState::load(uid)?;
+            let paths: Paths = paths.into();
+            match op {
+                EdgeOps::Ls { node } => {
+                    let graphs = Graphs::load_file_ro(&paths)?;
+                    let node = node.map(|n| graphs.resolve(&n)).transpose()?;
+                    for edge in graphs.data.edge_indices() {
+                        let (src, dst) = graphs.data.edge_endpoints(edge).unwrap();
+                        if node.is_some_and(|n| n != src && n != dst) {
+                            continue;
+                        }
+                        let label = match &graphs.data[edge] {
+                            Some(rel) => edge_label(edge, rel)?.join(", "),
+                            None => "(allocated)".to_owned(),
+                        };
+                        println!(
+                            "{} {} -> {}, {}",
+                            edge.index(),
+                            graphs.node_label(src),
+                            graphs.node_label(dst),
+                            label
+                        );
+                    }
+                }
+                EdgeOps::Add {
+                    node,
+                    tun2proxy,
+                    tun_to_unit,
+                    tun_to_socket,
+                    veth,
+                    to,
+                    socket,
+                    unit,
+                    listener,
+                } => {
+                    let tun_to = tun_recver(tun_to_unit, tun_to_socket.map(|p| cwd.join(p)));
+                    let passes = tun2proxy.is_some() || tun_to.is_some() || socket.is_some();
+                    if !passes && !veth && to.is_none() {
+                        bail!("Specify --tun2proxy, --tun-to-unit, --socket, --veth, or --to");
+                    }
+                    if socket.is_some() && unit.is_none() && listener.is_none() {
+                        bail!("Specify the receiver of the socket, with --unit or --listener");
+                    }
+                    let listener = listener.map(|p| cwd.join(p));
+                    let tun2proxy = tun2proxy.map(|t| t.canonicalize()).transpose()?;
+                    let rootful = geteuid().is_root();
+                    let pre = block_on(async { systemd_connection(rootful).await })??;
+                    let serv = systemd::Systemd::new(&paths, Some(pre), rootful)?;
+                    let usermnt = enter_userns(&paths)?;
+                    let mut graphs = Graphs::load_file(&paths)?;
+                    let src = graphs.resolve(&node)?;
+                    block_on(async {
+                        let ctx = serv.ctx().await?;
+                        let out = match &to {
+                            Some(o) => graphs.resolve(o)?,
+                            None => {
+                                graphs
+                                    .add_ns(
+                                        PidPath::Selfproc,
+                                        &paths,
+                                        usermnt.as_ref(),
+                                        NSAdd::RecordNothing,
+                                        None,
+                                        rootful,
+                                    )?
+                                    .1
+                            }
+                        };
+                        ensure!(src != out, "An edge must connect two nodes");
+                        if let Some(conf) = &tun2proxy {
+                            graphs
+                                .add_tun2proxy(src, out, conf, Some(pspath.clone()), &serv)
+                                .await?;
+                        }
+                        if let Some((recver, listener)) = tun_to {
+                            graphs
+                                .add_tun_recver(src, out, recver, listener, &serv)
+                                .await?;
+                        }
+                        if let Some(addr) = socket {
+                            let receiver = match &unit {
+                                Some(u) => FDRecver::Systemd(u.to_owned()),
+                                None => FDRecver::DontCare,
+                            };
+                            graphs
+                                .add_socket(src, out, SocketC { addr }, receiver, listener, &serv)
+                                .await?;
+                        }
+                        if veth || !passes {
+                            add_veth(&mut graphs, src, out, to.is_some()).await?;
+                        }
+                        graphs.dump_file(&paths, uid)?;
+                        // Re-run the probe, which passes FDs to the receivers
+                        let nw = graphs.nodewdeps(src)?;
+                        nw.write(Some(pspath.clone()), &serv).await?;
+                        serv.reload(&ctx).await?;
+                        nw.1.restart(&serv, &ctx).await?;
+                        nw.0.restart(&serv, &ctx).await?;
+                        aok!()
+                    })??;
+                }
+                EdgeOps::Rm { edge } => {
+                    let edge = EdgeI::from(edge);
+                    let rootful = geteuid().is_root();
+                    let pre = block_on(async { systemd_connection(rootful).await })??;
+                    let serv = systemd::Systemd::new(&paths, Some(pre), rootful)?;
+                    enter_userns(&paths)?;
+                    let mut graphs = Graphs::load_file(&paths)?;
+                    let (src, dst) = graphs
+                        .data
+                        .edge_endpoints(edge)
+                        .ok_or(anyhow!("specified edge does not exist"))?;
+                    block_on(async {
+                        let ctx = serv.ctx().await?;
+                        match &graphs.data[edge] {
+                            Some(Relation::Veth(v)) => remove_veth(&graphs, src, dst, v).await?,
+                            Some(rel) => match rel.fd_recver() {
+                                Some(FDRecver::TUN2Proxy(p)) => {
+                                    let s2t = Socks2TUN::new(p, edge)?;
+                                    if let Err(e) = s2t.stop(&serv, &ctx).await {
+                                        warn!("failed to stop tun2proxy of {:?}, {}", edge, e);
+                                    }
+                                }
+                                Some(FDRecver::Systemd(u)) => {
+                                    let ur = UnitRecver::new(u, edge);
+                                    if let Err(e) = ur.stop(&serv, &ctx).await {
+                                        warn!("failed to stop receiver of {:?}, {}", edge, e);
+                                    }
+                                }
+                                _ => (),
+                            },
+                            None => (),
+                        }
+                        graphs.edge_rm(edge, &serv).await?;
+                        graphs.dump_file(&paths, uid)?;
+                        // The probe must no longer require the units removed
+                        let nw = graphs.nodewdeps(src)?;
+                        nw.write(Some(pspath.clone()), &serv).await?;
+                        serv.reload(&ctx).await?;
+                        nw.0.restart(&serv, &ctx).await?;
+                        aok!()
+                    })??;
+                }
             }
         }
         Commands::Setns { pid, cmd, uid } => {
@@ -1501,7 +2475,7 @@ fn cmd(
         Commands::Sync => {
             let (pspath, paths): (PathBuf, PathState) = PathState::load(what_uid(None, true)?)?;
             let paths: Paths = paths.into();
-            let graphs = Graphs::load_file(&paths)?;
+            let graphs = Graphs::load_file_ro(&paths)?;
 
             block_on(async {
                 let rootful = geteuid().is_root();
@@ -1562,10 +2536,13 @@ fn cmd(
             cmd(
                 Cli {
                     log: None,
+                    output: cli.output,
                     sigint: 1,
                     command: Commands::New {
                         pid: None,
                         tun2proxy: Some(path.into()),
+                        tun_to_unit: None,
+                        tun_to_socket: None,
                         cmd: your_shell(None, Some(uid))?,
                         uid: Some(uid),
                         name: Some("geph".into()),
@@ -1576,6 +2553,12 @@ fn cmd(
                         userns: false,
                         associated: None,
                         assoc_ip: None,
+                        ipc: false,
+                        cgroup: false,
+                        time: false,
+                        pidns: false,
+                        hostname: None,
+                        machine_id: false,
                     },
                 },
                 cwd,
@@ -1616,10 +2599,13 @@ fn cmd(
             cmd(
                 Cli {
                     log: None,
+                    output: cli.output,
                     sigint: 1,
                     command: Commands::New {
                         pid: None,
                         tun2proxy: Some(path.into()),
+                        tun_to_unit: None,
+                        tun_to_socket: None,
                         cmd: your_shell(None, Some(uid))?,
                         uid: Some(uid),
                         name: None,
@@ -1630,6 +2616,12 @@ fn cmd(
                         set_dns: false,
                         associated: None,
                         assoc_ip: None,
+                        ipc: false,
+                        cgroup: false,
+                        time: false,
+                        pidns: false,
+                        hostname: None,
+                        machine_id: false,
                     },
                 },
                 cwd,
@@ -1640,6 +2632,7 @@ fn cmd(
         Commands::Librewolf => {
             let cli = Cli {
                 log: None,
+                output: cli.output,
                 sigint: 1,
                 command: Commands::Node {
                     id: Some(NodeAddr::Ix(0.into())),
@@ -1654,6 +2647,7 @@ fn cmd(
         Commands::Fractal => {
             let cli = Cli {
                 log: None,
+                output: cli.output,
                 sigint: 1,
                 command: Commands::Node {
                     id: Some(NodeAddr::Ix(0.into())),
@@ -1669,8 +2663,280 @@ fn cmd(
     })
 }
 
-fn summarize_graph(graphs: &Graphs) -> Result<()> {
+/// Links the nodes with a veth. The out end is in the NS you are in, unless `out_ns`.
+async fn add_veth(graphs: &mut Graphs, src: NodeI, out: NodeI, out_ns: bool) -> Result<()> {
+    let key: VPairKey = format!("v{}to{}", src.index(), out.index()).try_into()?;
+    let (_, deps) = graphs.nodewdeps(src)?;
+    if deps
+        .iter()
+        .any(|rel| matches!(rel.edge.item, Relation::Veth(v) if v.key == key))
+    {
+        bail!("Veth {} exists", key);
+    }
+    let net = |ix: NodeI| -> Result<ExactNS> {
+        Ok(graphs.data[ix].as_ref().unwrap().main.net.must()?.clone())
+    };
+    let (src_net, out_net) = (net(src)?, net(out)?);
+    graphs.route_edge(src, out, Route::Link)?;
+    let nl_src = nl_in_ns(&src_net).await?;
+    let nl_out = if out_ns {
+        nl_in_ns(&out_net).await?
+    } else {
+        NLHandle::new_self_proc_tokio()?
+    };
+    let vc = connect_ns_veth(nl_src, nl_out, Some(key)).await?;
+    let edge = graphs.data.add_edge(src, out, None);
+    let act = Action::AddEdge {
+        ix: edge,
+        src,
+        dst: out,
+        proxy: None,
+    };
+    graphs.record(&act);
+    graphs.data[edge].replace(Relation::Veth(vc));
+    graphs.done(&act);
+    Ok(())
+}
+
+/// Prunes the graphs. Links of removed nodes are removed through netlink, or recorded when planning
+async fn prune_with(
+    graphs: &mut Graphs,
+    paths: &PathState,
+    va: &mut VaCache,
+    serv: &mut systemd::Systemd,
+    plan: Option<&Recorder>,
+) -> Result<()> {
+    let mut rmnode = Default::default();
+    let mut nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
+    let ctx = NSGroup::proc_path(Selfproc, None)?;
+    nl.fill().await?;
+    graphs.prune(&ctx, va, serv, &mut rmnode, &mut nl).await?;
+    if let Some(rec) = plan {
+        graphs
+            .do_prune(&ctx, paths, serv, rmnode, &mut rec.clone())
+            .await?;
+    } else {
+        graphs.do_prune(&ctx, paths, serv, rmnode, &mut nl).await?;
+    }
+    Ok(())
+}
+
+/// Removes the veth from whichever end is reachable. Removing either end removes the pair.
+async fn remove_veth(graphs: &Graphs, src: NodeI, dst: NodeI, v: &VethConn) -> Result<()> {
+    for (ix, link) in [(src, v.key.link(LinkAB::A)), (dst, v.key.link(LinkAB::B))] {
+        let net = graphs.data[ix].as_ref().unwrap().main.net.must()?;
+        let mut nl = match nl_in_ns(net).await {
+            Ok(h) => NLDriver::new(h),
+            Err(e) => {
+                warn!("NetNS of node {} not available, {}", ix.index(), e);
+                continue;
+            }
+        };
+        nl.fill().await?;
+        if nl.links.contains_key(&link) {
+            LinkM::remove(&mut nl, &link).await?;
+            return Ok(());
+        }
+    }
+    warn!("Veth {} not found", v.key);
+    Ok(())
+}
+
+/// Removes nodes that are gone and unit files left without a node, or lists them for dry_run.
+/// Returns the processes to watch for the next collection.
+fn gc(paths: &Paths, serv: &systemd::Systemd, uid: u32, dry_run: bool) -> Result<Vec<pid_t>> {
+    // The lock is only held during a collection
+    let mut graphs = Graphs::load_file(paths)?;
+    let mut va = VaCache::default();
+    let mut rmnode: HashMap<NodeI, RM> = Default::default();
+    let pruned = block_on(async {
+        let mut nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
+        let ctx = NSGroup::proc_path(Selfproc, None)?;
+        nl.fill().await?;
+        graphs
+            .prune(&ctx, &mut va, serv, &mut rmnode, &mut nl)
+            .await?;
+        for (ni, rm) in &rmnode {
+            if let Some(reasons) = rm.reasons() {
+                let label = graphs.node_label(*ni);
+                if dry_run {
+                    println!("{}: {}", label, reasons.join(", "));
+                } else {
+                    info!("Collect node {}, {}", label, reasons.join(", "));
+                }
+            }
+        }
+        let pruned = rmnode.values().any(|rm| rm.reasons().is_some());
+        if !dry_run && pruned {
+            graphs.do_prune(&ctx, paths, serv, rmnode, &mut nl).await?;
+        }
+        // Left by nodes removed without us, or by a lost state file
+        let orphans = serv.orphans(&graphs)?;
+        if dry_run {
+            for path in &orphans {
+                println!("{}: no node or edge", path.display());
+            }
+        } else if !orphans.is_empty() {
+            let sctx = serv.ctx().await?;
+            for path in &orphans {
+                info!("Collect unit {}", path.display());
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if let Err(e) = serv.stop_unit(&sctx, &name).await {
+                    warn!("Stop {}, {}", name, e);
+                }
+                serv.remove_file_lenient(path)?;
+            }
+            serv.reload(&sctx).await?;
+        }
+        Ok::<_, anyhow::Error>(pruned)
+    })??;
+    // Idle collections must not rotate away the generations
+    if !dry_run && pruned {
+        graphs.dump_file(paths, uid)?;
+    }
+    Ok(graphs.holders())
+}
+
+/// Unshares the NSes nodes share with the host by default
+fn unshare_optional(ipc: bool, cgroup: bool, time: bool) -> Result<()> {
+    let mut flags = CloneFlags::empty();
+    flags.set(CloneFlags::CLONE_NEWIPC, ipc);
+    flags.set(CloneFlags::CLONE_NEWCGROUP, cgroup);
+    flags.set(NSTime::FLAG, time);
+    if flags.is_empty() {
+        return Ok(());
+    }
+    unshare(flags)?;
+    if time {
+        // Only children are put in the new time NS. Join it, so it shows at /proc/<pid>/ns/time
+        let fd = File::open("/proc/self/ns/time_for_children")?;
+        setns(fd, NSTime::FLAG)?;
+    }
+    Ok(())
+}
+
+/// Forks the init of a new PID NS, which returns to carry on as the process of the node.
+/// Its parent waits for it, and exits likewise.
+fn pidns_init(sc: &mut UnixStream) -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWPID)?;
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            prctl::set_pdeathsig(Some(SIGKILL))?;
+            // /proc is of the outer NS, where the parent knows us by this PID
+            let pid: i32 = std::fs::read_link("/proc/self")?
+                .to_string_lossy()
+                .parse()?;
+            sc.write_i32::<BigEndian>(pid)?; // 0
+            Ok(())
+        }
+        ForkResult::Parent { child } => {
+            let code = match waitpid(child, None)? {
+                WaitStatus::Exited(_, c) => c,
+                _ => 1,
+            };
+            exit(code)
+        }
+    }
+}
+
+/// As PID 1, reaps the command and the orphans reparented to it, until no child is left
+fn pidns_reap() -> Result<()> {
+    loop {
+        match waitpid(None, None) {
+            Ok(_) | Err(Errno::EINTR) => (),
+            Err(Errno::ECHILD) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Terminates the processes, and kills those left after the timeout
+fn stop_processes(pids: &[pid_t], timeout: Duration) -> Result<()> {
+    let alive = |p: &&pid_t| kill(Pid::from_raw(**p), None).is_ok();
+    for p in pids {
+        let _ = kill(Pid::from_raw(*p), Signal::SIGTERM);
+    }
+    let start = std::time::Instant::now();
+    while pids.iter().any(|p| alive(&p)) && start.elapsed() < timeout {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    for p in pids.iter().filter(alive) {
+        warn!("Kill {} {}", p, cmdline(*p));
+        let _ = kill(Pid::from_raw(*p), Signal::SIGKILL);
+    }
+    Ok(())
+}
+
+/// Receiver of the TUN, from --tun-to-unit or --tun-to-socket
+fn tun_recver(
+    unit: Option<String>,
+    socket: Option<PathBuf>,
+) -> Option<(FDRecver, Option<PathBuf>)> {
+    match (unit, socket) {
+        (Some(unit), _) => Some((FDRecver::Systemd(unit), None)),
+        (None, Some(socket)) => Some((FDRecver::DontCare, Some(socket))),
+        (None, None) => None,
+    }
+}
+
+/// Enters the out node of the edge, which is not ours when it is a hop of a chain.
+/// The user NS is joined first as probe does, as it owns the NetNS for rootless users.
+fn enter_out_net(paths: &Paths, edge: EdgeI) -> Result<()> {
+    let graphs = Graphs::load_file_ro(paths)?;
+    let (_, dst) = graphs
+        .data
+        .edge_endpoints(edge)
+        .ok_or(anyhow!("Edge {} does not exist", edge.index()))?;
+    let node = graphs.data[dst]
+        .as_ref()
+        .ok_or(anyhow!("Out node {} has been removed", dst.index()))?;
+    let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+    if node.main.net.must()?.unique == ctx.net.must()?.unique {
+        return Ok(());
+    }
+    let mut va = VaCache::default();
+    NSState {
+        target: &node.main,
+        va: &mut va,
+        held: HeldNS::connect(paths, dst),
+    }
+    .validated_enter()
+}
+
+/// Enters the persistent user and mnt NS as new does, since they own the nodes of rootless users
+fn enter_userns(paths: &Paths) -> Result<Option<NSGroup<ExactNS>>> {
+    let usern = paths.userns();
+    if geteuid().is_root() || !usern.exist()? {
+        return Ok(None);
+    }
+    let usermnt = usern.procns()?;
+    usermnt.enter(&NSGroup::proc_path(PidPath::Selfproc, None)?)?;
+    Ok(Some(usermnt))
+}
+
+/// Waits until one of the processes exits, or the timeout
+fn wait_exit(pids: &[pid_t], timeout: Duration) -> Result<()> {
+    // Processes that are already gone are left to the next collection
+    let fds: Vec<_> = pids
+        .iter()
+        .filter_map(|p| unsafe { pidfd::PidFd::open(*p, 0) }.ok())
+        .collect();
+    let mut polls: Vec<_> = fds
+        .iter()
+        .map(|f| PollFd::new(f, PollFlags::POLLIN))
+        .collect();
+    match poll(&mut polls, timeout.as_millis().try_into()?) {
+        Ok(_) | Err(Errno::EINTR) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn summarize_graph(graphs: &Graphs, output: OutputFormat) -> Result<()> {
     use owo_colors::OwoColorize;
+    if output == OutputFormat::Json {
+        let ctx = NSGroup::proc_path(Selfproc, None)?;
+        return GraphReport::new(graphs, Some(&ctx))?.print();
+    }
     Ok(for ni in graphs.data.node_indices() {
         let nwdeps = graphs.nodewdeps(ni)?;
         print!("{}", nwdeps.0);
@@ -1700,5 +2966,10 @@ fn summarize_graph(graphs: &Graphs) -> Result<()> {
         if nwdeps.1.len() == 0 {
             println!("      {}", "No dependencies".red());
         }
+        let chain = graphs.chain(ni);
+        if chain.len() > 2 {
+            let hops: Vec<_> = chain.into_iter().map(|n| graphs.node_label(n)).collect();
+            println!("      Chain {}", hops.join(" -> ").bright_cyan());
+        }
     })
 }
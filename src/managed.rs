@@ -6,7 +6,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use daggy::{
     petgraph::{
         visit::{EdgeRef, IntoEdgesDirected, IntoNodeReferences},
@@ -20,8 +20,12 @@ use tun::Layer;
 
 use super::*;
 use crate::{
-    data::{EdgeI, FDRecver, Graphs, Ix, NodeI, ObjectNode, Relation},
+    data::{
+        EdgeI, FDRecver, Graphs, Ix, NodeI, ObjectNode, PassFD, Relation, Route, SocketC, TUNC,
+    },
     paths::PathState,
+    plan::{Action, Planned},
+    sys::reachable_from,
     systemd::UnitName,
 };
 
@@ -124,12 +128,38 @@ struct Socks2TUN<'b> {
     confpath: &'b Path,
     /// It's possible to have multiple paths between one NS to another
     ix: EdgeIndex<Ix>,
+    /// Probe of the out node, when it is a hop of a chain
+    upstream: Option<String>,
 }
 
 #[public]
 impl<'b> Socks2TUN<'b> {
     fn new(confpath: &'b PathBuf, ix: EdgeIndex<Ix>) -> Result<Self> {
-        Ok(Self { confpath, ix })
+        Ok(Self {
+            confpath,
+            ix,
+            upstream: None,
+        })
+    }
+    /// Orders the receiver after the probe of the out node, and restarts it along
+    fn upstream(mut self, unit: Option<String>) -> Self {
+        self.upstream = unit;
+        self
+    }
+}
+
+/// A service of the user that receives FDs. It is activated through a socket unit of ours,
+/// so the probe can connect before the service runs.
+#[public]
+#[derive(new)]
+struct UnitRecver<'b> {
+    service: &'b str,
+    ix: EdgeIndex<Ix>,
+}
+
+impl<'b> UnitName for UnitRecver<'b> {
+    fn stem(&self) -> Result<String> {
+        Ok(format!("fdrecv{}", self.ix.index()))
     }
 }
 
@@ -171,11 +201,204 @@ impl Graphs {
         }
         Ok(())
     }
+    /// Adds an edge src --TUN--> out, and writes the tun2proxy units that receive the FD
+    async fn add_tun2proxy<S>(
+        &mut self,
+        src: NodeI,
+        out: NodeI,
+        conf: &PathBuf,
+        pspath: Option<PathBuf>,
+        serv: &S,
+    ) -> Result<EdgeI>
+    where
+        for<'b> Socks2TUN<'b>:
+            ItemCreate<Param = (Layer, Option<PathBuf>), Created = Relation, Serv = S>,
+    {
+        self.route_edge(src, out, Route::DefaultRoute)?;
+        let edge = self.data.add_edge(src, out, None);
+        let act = Action::AddEdge {
+            ix: edge,
+            src,
+            dst: out,
+            proxy: Some(conf.clone()),
+        };
+        self.record(&act);
+        log::info!("Src/Probe {src:?}, OutNode {out:?}, Src --TUN--> Out {edge:?}");
+        let socks2t = Socks2TUN::new(conf, edge)?.upstream(self.upstream(out)?);
+        let rel = socks2t.write((Layer::L3, pspath.clone()), serv).await?;
+        self.data[edge].replace(rel);
+        self.done(&act);
+        // Hops that route through src now come after this one
+        let up = self.upstream(src)?;
+        let downstream: Vec<_> = self
+            .data
+            .edges_directed(src, Direction::Incoming)
+            .filter_map(|e| match e.weight().as_ref().and_then(|r| r.fd_recver()) {
+                Some(FDRecver::TUN2Proxy(p)) => Some((e.id(), p.clone())),
+                _ => None,
+            })
+            .collect();
+        for (ix, p) in downstream {
+            Socks2TUN::new(&p, ix)?
+                .upstream(up.clone())
+                .write((Layer::L3, pspath.clone()), serv)
+                .await?;
+        }
+        Ok(edge)
+    }
+    /// Adds a listening socket, created in src by its probe and passed to the receiver in out
+    async fn add_socket<S>(
+        &mut self,
+        src: NodeI,
+        out: NodeI,
+        creation: SocketC,
+        receiver: FDRecver,
+        listener: Option<PathBuf>,
+        serv: &S,
+    ) -> Result<EdgeI>
+    where
+        for<'b> UnitRecver<'b>: ItemCreate<Param = (), Created = PathBuf, Serv = S>,
+    {
+        creation
+            .addr
+            .parse::<std::net::SocketAddr>()
+            .map_err(|e| anyhow!("Socket address {:?}, {}", creation.addr, e))?;
+        let make = |listener, receiver| {
+            Relation::SendSocket(PassFD {
+                creation,
+                listener,
+                receiver,
+            })
+        };
+        self.add_passfd(
+            src,
+            out,
+            Route::ListenedBy,
+            make,
+            (receiver, listener),
+            serv,
+        )
+        .await
+    }
+    /// Adds a TUN, created in src by its probe and passed to a receiver in out other than tun2proxy
+    async fn add_tun_recver<S>(
+        &mut self,
+        src: NodeI,
+        out: NodeI,
+        receiver: FDRecver,
+        listener: Option<PathBuf>,
+        serv: &S,
+    ) -> Result<EdgeI>
+    where
+        for<'b> UnitRecver<'b>: ItemCreate<Param = (), Created = PathBuf, Serv = S>,
+    {
+        let make = |listener, receiver| {
+            Relation::SendTUN(PassFD {
+                creation: TUNC {
+                    layer: Layer::L3,
+                    tun_name: Some(PROBE_TUN.to_owned()),
+                    mtu: None,
+                },
+                listener,
+                receiver,
+            })
+        };
+        let recver = (receiver, listener);
+        self.add_passfd(src, out, Route::DefaultRoute, make, recver, serv)
+            .await
+    }
+    /// Receivers other than systemd units must listen at `listener` themselves
+    async fn add_passfd<S>(
+        &mut self,
+        src: NodeI,
+        out: NodeI,
+        route: Route,
+        make: impl FnOnce(PathBuf, FDRecver) -> Relation,
+        (receiver, listener): (FDRecver, Option<PathBuf>),
+        serv: &S,
+    ) -> Result<EdgeI>
+    where
+        for<'b> UnitRecver<'b>: ItemCreate<Param = (), Created = PathBuf, Serv = S>,
+    {
+        if !matches!(receiver, FDRecver::Systemd(_)) && listener.is_none() {
+            bail!("Specify where the receiver listens");
+        }
+        self.route_edge(src, out, route)?;
+        let edge = self.data.add_edge(src, out, None);
+        let act = Action::AddEdge {
+            ix: edge,
+            src,
+            dst: out,
+            proxy: None,
+        };
+        self.record(&act);
+        let listener = match (&receiver, listener) {
+            (FDRecver::Systemd(unit), _) => UnitRecver::new(unit, edge).write((), serv).await?,
+            (_, l) => l.unwrap(),
+        };
+        log::info!("Src/Probe {src:?}, OutNode {out:?}, Src --FD--> {receiver} {edge:?}");
+        self.data[edge].replace(make(listener, receiver));
+        self.done(&act);
+        Ok(edge)
+    }
+    /// Probe of the node, if it routes through another hop
+    fn upstream(&self, node: NodeI) -> Result<Option<String>> {
+        let (place, deps) = self.nodewdeps(node)?;
+        Ok(if deps.iter().any(|d| d.edge.item.fd_recver().is_some()) {
+            Some(place.service()?)
+        } else {
+            None
+        })
+    }
+    /// Connects to the proxy from the out node, where tun2proxy runs.
+    /// A hop of a chain only reaches it once the hops before are up, so `doctor` checks it.
+    fn check_proxy(&self, conf: &Path, out: NodeI) -> Result<()> {
+        let conf: serde_json::Value = serde_json::from_reader(std::fs::File::open(conf)?)?;
+        let addr = conf
+            .pointer("/proxy/addr")
+            .and_then(|a| a.as_str())
+            .ok_or(anyhow!("proxy.addr is absent"))?;
+        let node = self.data[out]
+            .as_ref()
+            .ok_or(anyhow!("out node does not exist"))?;
+        reachable_from(node.main.net.must()?, addr)
+    }
+    /// Removes the edge, and the units of its FD receiver if we own them.
+    /// Does not stop anything.
+    async fn edge_rm<S>(&mut self, edge: EdgeI, serv: &S) -> Result<()>
+    where
+        for<'b> Socks2TUN<'b>: ItemRM<Serv = S>,
+        for<'b> UnitRecver<'b>: ItemRM<Serv = S>,
+    {
+        let rel = self
+            .data
+            .edge_weight(edge)
+            .ok_or(anyhow!("specified edge does not exist"))?;
+        match rel.as_ref().and_then(|r| r.fd_recver()) {
+            Some(FDRecver::TUN2Proxy(path)) => Socks2TUN::new(path, edge)?.remove(serv).await?,
+            Some(FDRecver::Systemd(unit)) => UnitRecver::new(unit, edge).remove(serv).await?,
+            _ => (),
+        }
+        let (src, dst) = self
+            .data
+            .edge_endpoints(edge)
+            .ok_or(anyhow!("specified edge does not exist"))?;
+        let act = Action::RemoveEdge { ix: edge, src, dst };
+        if self.record(&act) {
+            return Ok(());
+        }
+        self.data.remove_edge(edge);
+        self.done(&act);
+        self.rebuild_route();
+        Ok(())
+    }
     fn nodewdeps<'s: 'a + 'b, 'a, 'b>(&'s self, id: NodeI) -> Result<NodeWDeps<'a, 'b>> {
         let ed = self
             .data
             // A --push FD--> B
             .edges_directed(id, Direction::Outgoing)
+            // Allocated edges have nothing to run
+            .filter(|e| e.weight().is_some())
             .collect::<Vec<_>>();
         let ew = ed
             .iter()
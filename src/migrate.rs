@@ -0,0 +1,131 @@
+//! Versioning of the state file.
+//! Each change to the serialized form of [Graphs](crate::data::Graphs) bumps [SCHEMA_VERSION],
+//! and appends a migration from the previous version to [MIGRATIONS].
+
+use anyhow::bail;
+use serde_json::{json, Value};
+
+use super::*;
+
+pub const SCHEMA_VERSION: u32 = 5;
+
+type Migration = fn(&mut Value) -> Result<()>;
+
+/// MIGRATIONS[n] migrates from version n to n+1
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [v0_v1, v1_v2, v2_v3, v3_v4, v4_v5];
+
+/// Files before versioning. Only the version field is added.
+fn v0_v1(_graphs: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+/// NSGroup gained the ipc, cgroup and time slots
+fn v1_v2(graphs: &mut Value) -> Result<()> {
+    let nodes = graphs
+        .pointer_mut("/data/nodes")
+        .and_then(|n| n.as_array_mut());
+    for node in nodes.into_iter().flatten() {
+        if let Some(main) = node.get_mut("main").and_then(|m| m.as_object_mut()) {
+            for slot in ["ipc", "cgroup", "time"] {
+                main.entry(slot).or_insert(json!("Absent"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ObjectNode gained the identity
+fn v2_v3(graphs: &mut Value) -> Result<()> {
+    let nodes = graphs
+        .pointer_mut("/data/nodes")
+        .and_then(|n| n.as_array_mut());
+    for node in nodes.into_iter().flatten() {
+        if let Some(node) = node.as_object_mut() {
+            node.entry("identity").or_insert(Value::Null);
+        }
+    }
+    Ok(())
+}
+
+/// ExactNS gained the fingerprint. Entries without one are not checked for reuse
+fn v3_v4(_graphs: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+/// ObjectNode gained the spec it was applied from. Earlier nodes are taken as made by hand
+fn v4_v5(graphs: &mut Value) -> Result<()> {
+    let nodes = graphs
+        .pointer_mut("/data/nodes")
+        .and_then(|n| n.as_array_mut());
+    for node in nodes.into_iter().flatten() {
+        if let Some(node) = node.as_object_mut() {
+            node.entry("applied").or_insert(Value::Null);
+        }
+    }
+    Ok(())
+}
+
+/// Files without the field are version 0
+pub fn version(graphs: &Value) -> Result<u32> {
+    match graphs.get("version") {
+        None => Ok(0),
+        Some(v) => match v.as_u64() {
+            Some(v) => Ok(v.try_into()?),
+            None => bail!("version field of the state file is not a number"),
+        },
+    }
+}
+
+/// Brings the serialized graphs to the current version
+pub fn migrate(mut graphs: Value) -> Result<Value> {
+    let from = version(&graphs)?;
+    if from > SCHEMA_VERSION {
+        bail!(
+            "State file is of version {}, but this nsproxy supports up to {}. Upgrade nsproxy",
+            from,
+            SCHEMA_VERSION
+        );
+    }
+    for (v, mig) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        log::info!("Migrate state file from version {} to {}", v, v + 1);
+        mig(&mut graphs)?;
+    }
+    tag(&mut graphs)?;
+    Ok(graphs)
+}
+
+/// Marks the serialized graphs with the current version
+pub fn tag(graphs: &mut Value) -> Result<()> {
+    match graphs.as_object_mut() {
+        Some(obj) => {
+            obj.insert("version".to_owned(), json!(SCHEMA_VERSION));
+            Ok(())
+        }
+        None => bail!("state file is not a JSON object"),
+    }
+}
+
+#[test]
+fn migrate_versions() -> Result<()> {
+    let v0 = json!({ "map": {}, "name": {} });
+    let cur = migrate(v0)?;
+    assert_eq!(version(&cur)?, SCHEMA_VERSION);
+    let newer = json!({ "version": SCHEMA_VERSION + 1 });
+    assert!(migrate(newer).is_err());
+    Ok(())
+}
+
+#[test]
+fn migrate_slots() -> Result<()> {
+    let v1 = json!({
+        "version": 1,
+        "data": { "nodes": [null, { "name": null, "main": { "net": "Absent" }, "root": false }] }
+    });
+    let cur = migrate(v1)?;
+    assert_eq!(
+        cur.pointer("/data/nodes/1/main/ipc"),
+        Some(&json!("Absent"))
+    );
+    assert_eq!(cur.pointer("/data/nodes/0"), Some(&Value::Null));
+    Ok(())
+}
@@ -1,6 +1,5 @@
 use std::{
     fs::{create_dir_all, Permissions},
-    io::Read,
     os::unix::fs::{chown, PermissionsExt},
     path::{Path, PathBuf},
     sync::Arc,
@@ -8,14 +7,14 @@ use std::{
 
 use crate::{
     data::{Ix, NodeI},
-    sys::UserNS,
+    lock::{LockMode, StateLock},
+    sys::{atomic_write, UserNS},
 };
 
 use super::*;
 use anyhow::anyhow;
 use daggy::NodeIndex;
 use data::EdgeI;
-use fs4::FileExt;
 use nix::unistd::geteuid;
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
@@ -57,28 +56,26 @@ fn create_dirs_chown(path: &Path, uid: u32) -> Result<()> {
 impl PathState {
     pub fn dump_file(&self, pa: &Path) -> Result<()> {
         // info!("Dump PathState to {:?}", &pa);
-        let file = std::fs::File::create(&pa)?;
-        serde_json::to_writer_pretty(&file, self)?;
+        let _lock = StateLock::acquire(&Self::lockpath(pa), LockMode::Exclusive)?;
+        atomic_write(pa, &serde_json::to_vec_pretty(self)?, None)?;
         Ok(())
     }
     pub fn load_file(pa: &Path, uid: u32) -> Result<Self> {
         info!("Load PathState from {:?}", pa);
         if pa.exists() {
-            let mut file = std::fs::File::open(pa)?;
-            file.try_lock_exclusive()
-                .map_err(|_| anyhow!("State file locked"))?;
-            let mut st = Default::default();
-            file.read_to_string(&mut st)?;
+            let _lock = StateLock::acquire(&Self::lockpath(pa), LockMode::Shared)?;
+            let st = std::fs::read_to_string(pa)?;
             let g: Self = from_str(&st)?;
             Ok(g)
         } else {
-            let f = std::fs::File::create(&pa)?;
-            assert!(pa.exists());
-            f.try_lock_exclusive()
-                .map_err(|_| anyhow!("State file locked"))?;
+            std::fs::File::create(pa)?;
             PathState::default(uid)
         }
     }
+    /// The file is replaced on writes, so the lock is kept on a separate file
+    fn lockpath(pa: &Path) -> PathBuf {
+        pa.with_extension("lock")
+    }
     fn load(whatuid: u32) -> Result<(PathBuf, Self)> {
         info!("Loading pathstate for uid {}", whatuid);
         let pa = if let Ok(p) = std::env::var(PATH_VAR) {
@@ -131,9 +128,11 @@ impl PathState {
         Ok(())
     }
     fn mount(&self, id: NodeI, root: bool) -> Result<Binds> {
-        Ok(Binds(checked_path(
-            self.private(root)?.join(id.index().to_string()),
-        )?))
+        Ok(Binds(checked_path(self.mount_path(id, root)?)?))
+    }
+    /// Where [PathState::mount] binds, without creating it
+    fn mount_path(&self, id: NodeI, root: bool) -> Result<PathBuf> {
+        Ok(self.private(root)?.join(id.index().to_string()))
     }
     fn private(&self, root: bool) -> Result<PathBuf> {
         Ok(if root {
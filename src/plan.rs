@@ -0,0 +1,268 @@
+//! Planning mode. Side effects are recorded in order instead of being performed.
+//! The stand-ins are [Graphs](crate::data::Graphs) and [Systemd](crate::systemd::Systemd) holding a [Recorder],
+//! and the [Recorder] itself in place of netlink and mounts.
+//! They run the same code paths, checking [Planned::record] before each effect.
+//! Mutations of the in-memory graph still happen, but the state file is left untouched.
+//! When not planning, actions go to the [audit] log once they succeed, through [Planned::done].
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use ipnetwork::IpNetwork;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit,
+    data::{EdgeI, NodeI},
+};
+use nsproxy_common::UniqueFile;
+
+/// Collects the actions of the stand-ins sharing it
+#[derive(Clone, Default, Debug)]
+pub struct Recorder(Arc<Mutex<Vec<Action>>>);
+
+impl Recorder {
+    pub fn push(&self, action: Action) {
+        self.0.lock().unwrap().push(action);
+    }
+    /// The actions so far, in order
+    pub fn take(&self) -> Vec<Action> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Something that records instead of acting, when it holds a [Recorder]
+pub trait Planned {
+    fn recorder(&self) -> Option<&Recorder>;
+    /// Returns true when planning, in which case the action is recorded and the caller must skip the effect.
+    /// Otherwise, the caller performs it, then calls [Planned::done].
+    fn record(&self, action: &Action) -> bool {
+        if let Some(rec) = self.recorder() {
+            rec.push(action.clone());
+            true
+        } else {
+            false
+        }
+    }
+    /// The action succeeded, so it goes to the [audit] log. Nothing happens when planning
+    fn done(&self, action: &Action) {
+        if self.recorder().is_none() {
+            audit::record(action);
+        }
+    }
+}
+
+impl Planned for Option<Recorder> {
+    fn recorder(&self) -> Option<&Recorder> {
+        self.as_ref()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Action {
+    WriteFile {
+        path: PathBuf,
+        /// Left out of the audit log
+        #[serde(skip)]
+        contents: String,
+    },
+    RemoveFile(PathBuf),
+    /// Systemd daemon reload
+    Reload,
+    Restart(String),
+    Stop(String),
+    Mount {
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    Umount(PathBuf),
+    LinkUp(String),
+    Veth {
+        key: String,
+        ip_va: String,
+        ip_vb: String,
+        ip6_va: String,
+        ip6_vb: String,
+    },
+    MoveLink {
+        name: String,
+        to: UniqueFile,
+        rename: String,
+        addr: Option<IpNetwork>,
+    },
+    RemoveLink(String),
+    AddNode {
+        ix: NodeI,
+        name: Option<String>,
+        net: UniqueFile,
+        found: bool,
+    },
+    AddEdge {
+        ix: EdgeI,
+        src: NodeI,
+        dst: NodeI,
+        /// Config of the proxy, for TUN edges
+        proxy: Option<PathBuf>,
+    },
+    RemoveEdge {
+        ix: EdgeI,
+        src: NodeI,
+        dst: NodeI,
+    },
+    RemoveNode {
+        ix: NodeI,
+        name: Option<String>,
+        reasons: Vec<String>,
+    },
+    WriteState(PathBuf),
+    /// The process of a new node, in NSes of its own
+    Spawn {
+        cmd: Option<String>,
+        unshare: Vec<String>,
+        hostname: Option<String>,
+    },
+}
+
+impl Action {
+    pub fn write_file(path: &Path, contents: &[u8]) -> Self {
+        Self::WriteFile {
+            path: path.to_owned(),
+            contents: String::from_utf8_lossy(contents).into_owned(),
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WriteFile { path, contents } => {
+                f.write_fmt(format_args!("{} {:?}", "Write".green(), path.underline()))?;
+                // Absent in the audit log
+                if !contents.is_empty() {
+                    f.write_fmt(format_args!("\n{}", contents.trim_end()))?;
+                }
+                Ok(())
+            }
+            Self::RemoveFile(p) => f.write_fmt(format_args!("{} {:?}", "Remove".red(), p)),
+            Self::Reload => f.write_fmt(format_args!("{} systemd", "Reload".yellow())),
+            Self::Restart(u) => {
+                f.write_fmt(format_args!("{} {}", "Restart".yellow(), u.bright_purple()))
+            }
+            Self::Stop(u) => f.write_fmt(format_args!("{} {}", "Stop".red(), u.bright_purple())),
+            Self::Mount { src, dst } => {
+                f.write_fmt(format_args!("{} {:?} onto {:?}", "Bind".green(), src, dst))
+            }
+            Self::Umount(p) => f.write_fmt(format_args!("{} {:?}", "Umount".red(), p)),
+            Self::LinkUp(l) => f.write_fmt(format_args!("{} {}", "Link up".green(), l)),
+            Self::Veth {
+                key,
+                ip_va,
+                ip_vb,
+                ip6_va,
+                ip6_vb,
+            } => f.write_fmt(format_args!(
+                "{} {}, {} {} in, {} {} out",
+                "Veth".green(),
+                key.yellow(),
+                ip_va.bright_blue(),
+                ip6_va.bright_blue(),
+                ip_vb.bright_blue(),
+                ip6_vb.bright_blue()
+            )),
+            Self::MoveLink {
+                name,
+                to,
+                rename,
+                addr,
+            } => f.write_fmt(format_args!(
+                "{} {} into NetNS {}, as {} with {:?}",
+                "Move".green(),
+                name.yellow(),
+                to,
+                rename.yellow(),
+                addr
+            )),
+            Self::RemoveLink(l) => f.write_fmt(format_args!("{} link {}", "Remove".red(), l)),
+            Self::AddNode {
+                ix,
+                name,
+                net,
+                found,
+            } => f.write_fmt(format_args!(
+                "{} node {} {:?}, NetNS {}",
+                if *found {
+                    "Update".yellow().to_string()
+                } else {
+                    "Add".green().to_string()
+                },
+                ix.index().bright_yellow(),
+                name,
+                net
+            )),
+            Self::AddEdge {
+                ix,
+                src,
+                dst,
+                proxy,
+            } => {
+                f.write_fmt(format_args!(
+                    "{} edge {} from {} to {}",
+                    "Add".green(),
+                    ix.index(),
+                    src.index(),
+                    dst.index()
+                ))?;
+                if let Some(p) = proxy {
+                    f.write_fmt(format_args!(", via {:?}", p))?;
+                }
+                Ok(())
+            }
+            Self::RemoveEdge { ix, src, dst } => f.write_fmt(format_args!(
+                "{} edge {} from {} to {}",
+                "Remove".red(),
+                ix.index(),
+                src.index(),
+                dst.index()
+            )),
+            Self::RemoveNode { ix, name, reasons } => f.write_fmt(format_args!(
+                "{} node {} {:?}, {}",
+                "Remove".red(),
+                ix.index(),
+                name,
+                reasons.join(", ")
+            )),
+            Self::WriteState(p) => f.write_fmt(format_args!("{} {:?}", "Save".green(), p)),
+            Self::Spawn {
+                cmd,
+                unshare,
+                hostname,
+            } => {
+                f.write_fmt(format_args!(
+                    "{} {} in new {}",
+                    "Spawn".green(),
+                    cmd.as_deref().unwrap_or("the shell").yellow(),
+                    unshare.join(", ")
+                ))?;
+                if let Some(h) = hostname {
+                    f.write_fmt(format_args!(", with hostname {}", h))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn stand_in() {
+    let rec = Recorder::default();
+    let planned = Some(rec.clone());
+    assert!(planned.record(&Action::Reload));
+    assert!(!None::<Recorder>.record(&Action::Reload));
+    assert!(matches!(rec.take()[..], [Action::Reload]));
+    assert!(rec.take().is_empty());
+}
@@ -3,13 +3,13 @@
 use std::{
     borrow::Borrow,
     io::Write,
+    net::TcpListener,
     os::{
         fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
         unix::net::UnixStream,
     },
 };
 
-use nix::sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType};
 use passfd::FdPassingExt;
 use tun::{Configuration, Device};
 
@@ -59,13 +59,10 @@ impl PassFD<TUNC> {
 }
 
 impl PassFD<SocketC> {
+    /// Binds and listens in the current NetNS, so the receiver only has to accept
     pub fn pass(&self) -> Result<()> {
-        let sock = socket(
-            AddressFamily::Inet,
-            SockType::Stream,
-            SockFlag::SOCK_CLOEXEC,
-            SockProtocol::Tcp,
-        )?;
+        let sock = TcpListener::bind(&self.creation.addr)?;
+        log::info!("Listening on {:?}", sock.local_addr()?);
         self.connect_and_pass(sock.as_fd())?;
 
         Ok(())
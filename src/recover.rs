@@ -0,0 +1,222 @@
+//! Rebuilds the graphs from what is left in the system, when the state file is lost.
+//! Nodes come from the mounted NSes, veths from links of the host and the nodes, and tun2proxy
+//! relations from units. Edges of other receiver units are kept allocated, as the units do not tell
+//! their kind or out node. Names, and relations that left nothing in the system, are not recovered.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::bail;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use netlink_ops::{
+    netlink::{LinkAB, NLDriver, VPairKey, VethConn},
+    state::ExpCollection,
+};
+use tracing::{info, warn};
+
+use super::*;
+use crate::data::{
+    EdgeI, ExactNS, FDRecver, Graphs, Ix, NSGroup, NodeI, ObjectNode, PassFD, Relation, Route, TUNC,
+};
+
+/// A tun2proxy relation found in units
+#[public]
+#[derive(Debug)]
+struct FoundTUN {
+    edge: Ix,
+    src: Ix,
+    /// None for the host
+    out: Option<Ix>,
+    conf: PathBuf,
+    listener: PathBuf,
+}
+
+/// A receiver unit of an edge, which does not tell the kind or out node of it
+#[public]
+#[derive(Debug)]
+struct FoundRecver {
+    edge: Ix,
+    /// Node of the probe requiring it
+    src: Option<Ix>,
+    service: String,
+}
+
+/// Conf path and edge from ExecStart of a tun2proxy unit
+pub fn parse_exec(exec: &str) -> Option<(PathBuf, Ix)> {
+    let (_, rest) = exec.split_once(" tun2proxy systemd ")?;
+    let (conf, id) = rest.rsplit_once(" --id ")?;
+    let conf = conf.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((conf.into(), id.trim().parse().ok()?))
+}
+
+/// Indices of the veth key in the name of a link, if it is the end in the out node
+fn veth_ends(name: &str) -> Option<(Ix, Ix, VPairKey)> {
+    let (src, rest) = name.strip_prefix('v')?.split_once("to")?;
+    let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+    let (src, out): (Ix, Ix) = (src.parse().ok()?, rest[..digits].parse().ok()?);
+    let key: VPairKey = format!("v{}to{}", src, out).try_into().ok()?;
+    (key.link(LinkAB::B).0 == name).then_some((src, out, key))
+}
+
+/// Veths whose out end is in the current NetNS. Addresses of the other end follow from the subnet.
+pub fn found_veths(nl: &NLDriver) -> Result<Vec<(Ix, Ix, VethConn)>> {
+    let mut found = Vec::new();
+    for (k, dev) in &nl.links {
+        let Some((src, out, key)) = veth_ends(&k.0) else {
+            continue;
+        };
+        let Ok(dev) = dev.exist_ref() else {
+            continue;
+        };
+        let ExpCollection::Filled(addrs) = &dev.addrs else {
+            continue;
+        };
+        let (mut v4, mut v6) = (None, None);
+        for addr in addrs.keys() {
+            match addr {
+                IpNetwork::V4(a) if a.prefix() == 30 => v4 = Some(*a),
+                IpNetwork::V6(a) if a.prefix() == 126 => v6 = Some(*a),
+                _ => (),
+            }
+        }
+        let (Some(vb), Some(vb6)) = (v4, v6) else {
+            warn!("Veth {} lacks its addresses", key);
+            continue;
+        };
+        let net4 = Ipv4Network::new(vb.network(), 30)?;
+        let net6 = Ipv6Network::new(vb6.network(), 126)?;
+        found.push((
+            src,
+            out,
+            VethConn {
+                subnet_veth: net4.into(),
+                subnet6_veth: net6.into(),
+                ip_va: Ipv4Network::new(net4.network(), 30)?.into(),
+                ip_vb: vb.into(),
+                ip6_va: Ipv6Network::new(net6.network(), 126)?.into(),
+                ip6_vb: vb6.into(),
+                key,
+            },
+        ));
+    }
+    Ok(found)
+}
+
+#[public]
+impl Graphs {
+    /// Rebuilds the graphs, keeping the indices that mounts and units are named after.
+    /// host is the node for the current NSes, which is the out node of relations with none recorded.
+    fn rebuild(
+        &mut self,
+        mounted: HashMap<Ix, NSGroup<ExactNS>>,
+        host: NSGroup<ExactNS>,
+        tuns: Vec<FoundTUN>,
+        recvers: Vec<FoundRecver>,
+        veths: Vec<(Ix, Ix, VethConn)>,
+        root: bool,
+    ) -> Result<()> {
+        if self.data.node_count() > 0 {
+            bail!("Graphs must be empty to be rebuilt");
+        }
+        // Veths to the host record its index
+        let host_ix = veths.iter().map(|v| v.1).find(|o| !mounted.contains_key(o));
+        let mut nodes: HashMap<Ix, NSGroup<ExactNS>> = mounted;
+        let host_ix = match host_ix {
+            Some(ix) => ix,
+            None => nodes.keys().max().map_or(0, |m| m + 1),
+        };
+        nodes.insert(host_ix, host);
+        let max = nodes.keys().copied().max().unwrap_or_default();
+        for _ in 0..=max {
+            self.data.add_node(None);
+        }
+        let live: HashSet<Ix> = nodes.keys().copied().collect();
+        for (ix, main) in nodes {
+            let ni = NodeI::from(ix);
+            self.map.insert(main.key(), ni);
+            self.data[ni] = Some(ObjectNode {
+                name: None,
+                main,
+                root,
+                identity: None,
+                applied: None,
+            });
+            info!("Recovered node {}", ix);
+        }
+        let node = |ix: Option<Ix>| -> Option<NodeI> {
+            let ix = ix.unwrap_or(host_ix);
+            live.contains(&ix).then(|| NodeI::from(ix))
+        };
+        let mut rels = Vec::new();
+        for t in tuns {
+            let (Some(src), Some(out)) = (node(Some(t.src)), node(t.out)) else {
+                warn!("Nodes of tun2proxy edge {} are not mounted", t.edge);
+                continue;
+            };
+            let rel = Relation::SendTUN(PassFD {
+                creation: TUNC {
+                    layer: tun::Layer::L3,
+                    tun_name: Some(PROBE_TUN.to_owned()),
+                    mtu: None,
+                },
+                receiver: FDRecver::TUN2Proxy(t.conf),
+                listener: t.listener,
+            });
+            rels.push((Some(t.edge), src, out, Some(rel), Route::DefaultRoute));
+        }
+        let first = NodeI::from(host_ix);
+        let mut reserved = HashSet::new();
+        for r in recvers {
+            warn!(
+                "Edge {} to {} is kept allocated, as its kind and out node are unknown",
+                r.edge, r.service
+            );
+            let src = node(r.src).unwrap_or(first);
+            reserved.insert(r.edge as usize);
+            rels.push((Some(r.edge), src, first, None, Route::Link));
+        }
+        for (src, out, vc) in veths {
+            let (Some(src), Some(out)) = (node(Some(src)), node(Some(out))) else {
+                warn!("Nodes of veth {} are not mounted", vc.key);
+                continue;
+            };
+            rels.push((None, src, out, Some(Relation::Veth(vc)), Route::Link));
+        }
+        // Units are named after the edge, so those come first, in order
+        rels.sort_by_key(|r| (r.0.is_none(), r.0));
+        for (ix, src, out, rel, route) in rels {
+            if let Some(ix) = ix {
+                if self.data.edge_count() > ix as usize {
+                    warn!("Edge {} is found twice", ix);
+                    continue;
+                }
+                while self.data.edge_count() < ix as usize {
+                    self.data.add_edge(first, first, None);
+                }
+            }
+            let Some(rel) = rel else {
+                self.data.add_edge(src, out, None);
+                continue;
+            };
+            if let Err(e) = self.route_edge(src, out, route) {
+                warn!("{}", e);
+                continue;
+            }
+            let edge: EdgeI = self.data.add_edge(src, out, Some(rel));
+            info!("Recovered edge {}", edge.index());
+        }
+        self.data
+            .retain_edges(|g, e| g[e].is_some() || reserved.contains(&e.index()));
+        self.data.retain_nodes(|g, n| g[n].is_some());
+        Ok(())
+    }
+}
+
+#[test]
+fn exec_line() {
+    let exec = r#""/usr/local/bin/nsproxy" tun2proxy systemd "/home/u/proxy.json" --id 3"#;
+    assert_eq!(parse_exec(exec), Some(("/home/u/proxy.json".into(), 3)));
+    assert_eq!(parse_exec(r#""/usr/local/bin/nsproxy" probe 3"#), None);
+}
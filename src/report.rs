@@ -0,0 +1,629 @@
+//! Machine readable views of the graph, for `--output json`.
+//! The schema is stable. Fields may be added, but never renamed or removed without bumping [REPORT_VERSION].
+
+use std::{fmt::Display, path::PathBuf};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use super::*;
+use crate::{
+    data::{
+        ExactNS, FDRecver, Graphs, Ix, NSGroup, NSSlot, NSTrait, NodeI, Relation, Validate,
+        ValidateR,
+    },
+    graph::{Holder, NetNSInfo},
+    managed::{NodeIndexed, Socks2TUN},
+    sys::cmdline,
+    systemd::UnitName,
+};
+use nsproxy_common::{cached_fstat, cached_stat, NSSource, UniqueFile, VaCache, ValidationErr};
+
+pub const REPORT_VERSION: u32 = 1;
+
+/// Reports are printed as JSON alike
+pub trait Report: Serialize {
+    fn print(&self) -> Result<()> {
+        println!("{}", serde_json::to_string_pretty(self)?);
+        Ok(())
+    }
+}
+
+impl Report for GraphReport {}
+impl Report for CheckReport {}
+impl Report for DepsReport {}
+impl Report for PsReport {}
+impl Report for NetNSListReport {}
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct GraphReport {
+    version: u32,
+    nodes: Vec<NodeReport>,
+    /// Set when the graph could not be loaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct NodeReport {
+    id: Ix,
+    name: Option<String>,
+    root: bool,
+    /// The probe unit
+    unit: String,
+    namespaces: Vec<NSReport>,
+    relations: Vec<RelationReport>,
+    /// Nodes the traffic goes through, starting with this one
+    chain: Vec<Ix>,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct NSReport {
+    /// net, uts, pid, user, mnt, ipc, cgroup, or time
+    kind: &'static str,
+    unique: UniqueFile,
+    source: SourceReport,
+    /// What the source stats to now. Absent when validation was not requested, or it can not be stated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    observed: Option<UniqueFile>,
+    /// Absent when validation was not requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validation: Option<ValidationReport>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceReport {
+    Pid { pid: pid_t },
+    Path { path: PathBuf },
+    Unavail { ioctl: bool },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ValidationReport {
+    Pass,
+    /// The NS can not be checked from here, ex. it has no source
+    Unspec,
+    Impossible,
+    /// The NS no longer matches the record
+    Invalid {
+        reason: InvalidReason,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// [ValidationErr], named independently of the variants
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidReason {
+    InoMismatch,
+    FileNonExist,
+    FileNonExistProc,
+    ProcessGone,
+    Permission,
+    Reused,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct RelationReport {
+    edge: Ix,
+    to: Ix,
+    to_name: Option<String>,
+    #[serde(flatten)]
+    kind: RelationKind,
+    /// Units that run the FD receiver
+    units: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RelationKind {
+    Veth {
+        key: String,
+        subnet: String,
+        subnet6: String,
+        /// Addresses of the end in the source node
+        ip_in: String,
+        ip6_in: String,
+        ip_out: String,
+        ip6_out: String,
+    },
+    Tun {
+        layer: String,
+        tun_name: Option<String>,
+        mtu: Option<u32>,
+        listener: PathBuf,
+        receiver: ReceiverReport,
+    },
+    Socket {
+        addr: String,
+        listener: PathBuf,
+        receiver: ReceiverReport,
+    },
+    /// Relation that is allocated but not filled
+    Pending,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReceiverReport {
+    Tun2proxy { config: PathBuf },
+    Systemd { unit: String },
+    DontCare,
+}
+
+/// Validates namespaces against the context when provided
+pub struct Check<'c> {
+    cache: VaCache,
+    ctx: &'c NSGroup<ExactNS>,
+}
+
+impl<'c> Check<'c> {
+    pub fn new(ctx: &'c NSGroup<ExactNS>) -> Self {
+        Self {
+            cache: VaCache::default(),
+            ctx,
+        }
+    }
+}
+
+impl From<&NSSource> for SourceReport {
+    fn from(value: &NSSource) -> Self {
+        match value {
+            NSSource::Pid(pid) => Self::Pid { pid: *pid },
+            NSSource::Path(path) => Self::Path { path: path.clone() },
+            NSSource::Unavail(ioctl) => Self::Unavail { ioctl: *ioctl },
+        }
+    }
+}
+
+impl From<Result<ValidateR>> for ValidationReport {
+    fn from(value: Result<ValidateR>) -> Self {
+        match value {
+            Ok(ValidateR::Pass) => Self::Pass,
+            Ok(ValidateR::Unspec) => Self::Unspec,
+            Ok(ValidateR::Impossible) => Self::Impossible,
+            Err(e) => match e.downcast_ref::<ValidationErr>() {
+                Some(v) => Self::Invalid { reason: v.into() },
+                None => Self::Error {
+                    message: e.to_string(),
+                },
+            },
+        }
+    }
+}
+
+impl From<&ValidationErr> for InvalidReason {
+    fn from(value: &ValidationErr) -> Self {
+        match value {
+            ValidationErr::InoMismatch => Self::InoMismatch,
+            ValidationErr::FileNonExist => Self::FileNonExist,
+            ValidationErr::FileNonExistProc => Self::FileNonExistProc,
+            ValidationErr::ProcessGone => Self::ProcessGone,
+            ValidationErr::Permission => Self::Permission,
+            ValidationErr::Reused => Self::Reused,
+        }
+    }
+}
+
+fn ns_report<K: NSTrait>(slot: &NSSlot<ExactNS, K>, check: &mut Option<Check>) -> Option<NSReport> {
+    match slot {
+        NSSlot::Absent => None,
+        NSSlot::Provided(ns, _) => Some(NSReport {
+            kind: K::NAME,
+            unique: ns.unique,
+            source: (&ns.source).into(),
+            observed: check.as_mut().and_then(|c| observed(ns, c)),
+            validation: check
+                .as_mut()
+                .map(|c| ns.validate(&mut c.cache, c.ctx).into()),
+        }),
+    }
+}
+
+/// Stat of the source, as validation compares it
+fn observed(ns: &ExactNS, c: &mut Check) -> Option<UniqueFile> {
+    let st = match &ns.source {
+        NSSource::Path(p) => cached_stat(&mut c.cache, (p, &c.ctx.mnt.must().ok()?.unique)),
+        NSSource::Pid(p) => cached_fstat(&mut c.cache, (*p, &c.ctx.pid.must().ok()?.unique)),
+        NSSource::Unavail(_) => return None,
+    };
+    st.ok().map(|st| (*st).into())
+}
+
+fn namespaces(main: &NSGroup<ExactNS>, check: &mut Option<Check>) -> Vec<NSReport> {
+    [
+        ns_report(&main.net, check),
+        ns_report(&main.uts, check),
+        ns_report(&main.pid, check),
+        ns_report(&main.user, check),
+        ns_report(&main.mnt, check),
+        ns_report(&main.ipc, check),
+        ns_report(&main.cgroup, check),
+        ns_report(&main.time, check),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn receiver(fdr: &FDRecver) -> ReceiverReport {
+    match fdr {
+        FDRecver::TUN2Proxy(p) => ReceiverReport::Tun2proxy { config: p.clone() },
+        FDRecver::Systemd(s) => ReceiverReport::Systemd { unit: s.clone() },
+        FDRecver::DontCare => ReceiverReport::DontCare,
+    }
+}
+
+impl RelationKind {
+    fn new(rel: Option<&Relation>) -> Self {
+        match rel {
+            None => Self::Pending,
+            Some(Relation::Veth(v)) => Self::Veth {
+                key: v.key.to_string(),
+                subnet: v.subnet_veth.to_string(),
+                subnet6: v.subnet6_veth.to_string(),
+                ip_in: v.ip_va.to_string(),
+                ip6_in: v.ip6_va.to_string(),
+                ip_out: v.ip_vb.to_string(),
+                ip6_out: v.ip6_vb.to_string(),
+            },
+            Some(Relation::SendTUN(p)) => Self::Tun {
+                layer: format!("{:?}", p.creation.layer).to_lowercase(),
+                tun_name: p.creation.tun_name.clone(),
+                mtu: p.creation.mtu,
+                listener: p.listener.clone(),
+                receiver: receiver(&p.receiver),
+            },
+            Some(Relation::SendSocket(p)) => Self::Socket {
+                addr: p.creation.addr.clone(),
+                listener: p.listener.clone(),
+                receiver: receiver(&p.receiver),
+            },
+        }
+    }
+}
+
+/// Relations of the node to its dependencies, in the order `node deps` indexes them
+fn relations(graphs: &Graphs, ni: NodeI) -> Result<Vec<RelationReport>> {
+    let (_, deps) = graphs.nodewdeps(ni)?;
+    let mut relations = Vec::with_capacity(deps.len());
+    for dep in deps {
+        let mut units = Vec::new();
+        match dep.edge.item.fd_recver() {
+            Some(FDRecver::TUN2Proxy(path)) => {
+                let s2t = Socks2TUN::new(path, dep.edge.id)?;
+                units.push(s2t.service()?);
+                units.push(s2t.sockunit()?);
+            }
+            Some(FDRecver::Systemd(s)) => units.push(s.clone()),
+            _ => (),
+        }
+        relations.push(RelationReport {
+            edge: dep.edge.id.index() as Ix,
+            to: dep.dst.id.index() as Ix,
+            to_name: dep.dst.item.name.clone(),
+            kind: RelationKind::new(Some(dep.edge.item)),
+            units,
+        });
+    }
+    Ok(relations)
+}
+
+#[public]
+impl NodeReport {
+    fn new(graphs: &Graphs, node: NodeIndexed, check: &mut Option<Check>) -> Result<Self> {
+        let namespaces = namespaces(&node.item.main, check);
+        Ok(Self {
+            id: node.id.index() as Ix,
+            name: node.item.name.clone(),
+            root: node.item.root,
+            unit: node.service()?,
+            namespaces,
+            relations: relations(graphs, node.id)?,
+            chain: graphs
+                .chain(node.id)
+                .into_iter()
+                .map(|n| n.index() as Ix)
+                .collect(),
+        })
+    }
+}
+
+#[public]
+impl GraphReport {
+    /// Namespaces are validated against ctx, if provided
+    fn new(graphs: &Graphs, ctx: Option<&NSGroup<ExactNS>>) -> Result<Self> {
+        let mut check = ctx.map(Check::new);
+        let mut nodes = Vec::new();
+        for ni in graphs.data.node_indices() {
+            if let Some(item) = &graphs.data[ni] {
+                nodes.push(NodeReport::new(
+                    graphs,
+                    NodeIndexed::new(ni, item),
+                    &mut check,
+                )?);
+            }
+        }
+        Ok(Self {
+            version: REPORT_VERSION,
+            nodes,
+            error: None,
+        })
+    }
+    fn error(err: &anyhow::Error) -> Self {
+        Self {
+            version: REPORT_VERSION,
+            nodes: Vec::new(),
+            error: Some(format!("{:?}", err)),
+        }
+    }
+}
+
+/// Validation of a node, for `node check`
+#[public]
+#[derive(Serialize, Debug)]
+struct CheckReport {
+    version: u32,
+    id: Ix,
+    name: Option<String>,
+    namespaces: Vec<NSReport>,
+    /// False if any NS is invalid, or failed to be checked
+    pass: bool,
+}
+
+#[public]
+impl CheckReport {
+    fn new(graphs: &Graphs, ni: NodeI, ctx: &NSGroup<ExactNS>) -> Result<Self> {
+        let item = graphs.data[ni]
+            .as_ref()
+            .ok_or(anyhow!("Node {} does not exist", ni.index()))?;
+        let namespaces = namespaces(&item.main, &mut Some(Check::new(ctx)));
+        let pass = !namespaces.iter().any(|n| {
+            matches!(
+                n.validation,
+                Some(ValidationReport::Invalid { .. } | ValidationReport::Error { .. })
+            )
+        });
+        Ok(Self {
+            version: REPORT_VERSION,
+            id: ni.index() as Ix,
+            name: item.name.clone(),
+            namespaces,
+            pass,
+        })
+    }
+}
+
+/// Dependencies of a node, for `node deps`
+#[public]
+#[derive(Serialize, Debug)]
+struct DepsReport {
+    version: u32,
+    id: Ix,
+    name: Option<String>,
+    relations: Vec<RelationReport>,
+}
+
+#[public]
+impl DepsReport {
+    fn new(graphs: &Graphs, ni: NodeI) -> Result<Self> {
+        let item = graphs.data[ni]
+            .as_ref()
+            .ok_or(anyhow!("Node {} does not exist", ni.index()))?;
+        Ok(Self {
+            version: REPORT_VERSION,
+            id: ni.index() as Ix,
+            name: item.name.clone(),
+            relations: relations(graphs, ni)?,
+        })
+    }
+}
+
+/// Processes of a node, for `node ps`
+#[public]
+#[derive(Serialize, Debug)]
+struct PsReport {
+    version: u32,
+    id: Ix,
+    name: Option<String>,
+    /// Kind of the NS they were found by, pid or net
+    by: &'static str,
+    processes: Vec<ProcessReport>,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct ProcessReport {
+    #[serde(flatten)]
+    holder: Holder,
+    cmdline: String,
+}
+
+#[public]
+impl PsReport {
+    fn new(graphs: &Graphs, ni: NodeI, ctx: &NSGroup<ExactNS>) -> Result<Self> {
+        let item = graphs.data[ni]
+            .as_ref()
+            .ok_or(anyhow!("Node {} does not exist", ni.index()))?;
+        let (by, pids) = graphs.processes(ni, ctx)?;
+        let processes = pids
+            .into_iter()
+            .filter_map(Holder::of)
+            .map(|holder| ProcessReport {
+                cmdline: cmdline(holder.pid),
+                holder,
+            })
+            .collect();
+        Ok(Self {
+            version: REPORT_VERSION,
+            id: ni.index() as Ix,
+            name: item.name.clone(),
+            by,
+            processes,
+        })
+    }
+}
+
+impl Display for RelationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Veth { key, .. } => write!(f, "veth {}", key),
+            Self::Tun { receiver, .. } => write!(f, "tun to {}", receiver),
+            Self::Socket { addr, receiver, .. } => write!(f, "socket {} to {}", addr, receiver),
+            Self::Pending => f.write_str("pending"),
+        }
+    }
+}
+
+impl Display for ReceiverReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tun2proxy { config } => write!(f, "tun2proxy {}", config.display()),
+            Self::Systemd { unit } => write!(f, "{}", unit),
+            Self::DontCare => f.write_str("unknown"),
+        }
+    }
+}
+
+impl Display for InvalidReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::InoMismatch => "ino_mismatch",
+            Self::FileNonExist => "file_non_exist",
+            Self::FileNonExistProc => "file_non_exist_proc",
+            Self::ProcessGone => "process_gone",
+            Self::Permission => "permission",
+            Self::Reused => "reused",
+        })
+    }
+}
+
+impl Display for SourceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pid { pid } => write!(f, "pid {}", pid),
+            Self::Path { path } => write!(f, "{}", path.display()),
+            Self::Unavail { .. } => f.write_str("unavailable"),
+        }
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pass => f.write_str("pass"),
+            Self::Unspec => f.write_str("unchecked"),
+            Self::Impossible => f.write_str("impossible"),
+            Self::Invalid { reason } => write!(f, "invalid, {}", reason),
+            Self::Error { message } => write!(f, "error, {}", message),
+        }
+    }
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct NetNSListReport {
+    version: u32,
+    netns: Vec<NetNSReport>,
+}
+
+#[public]
+#[derive(Serialize, Debug)]
+struct NetNSReport {
+    #[serde(flatten)]
+    info: NetNSInfo,
+    /// The node it maps to. Absent for foreign NSes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<Ix>,
+}
+
+#[public]
+impl NetNSListReport {
+    fn new(graphs: &Graphs, found: Vec<NetNSInfo>) -> Self {
+        let netns = found
+            .into_iter()
+            .map(|info| NetNSReport {
+                node: graphs.map.get(&info.unique).map(|ni| ni.index() as Ix),
+                info,
+            })
+            .collect();
+        Self {
+            version: REPORT_VERSION,
+            netns,
+        }
+    }
+}
+
+/// Field names are part of the schema
+#[test]
+fn report_schema() -> Result<()> {
+    use crate::data::{ObjectNode, PidPath};
+    let keys = |v: &serde_json::Value| {
+        let mut k: Vec<_> = v.as_object().unwrap().keys().cloned().collect();
+        k.sort();
+        k
+    };
+    let mut graphs = Graphs::default();
+    let ni = graphs.data.add_node(Some(ObjectNode {
+        name: Some("a".to_owned()),
+        main: NSGroup::default(),
+        root: false,
+        identity: None,
+        applied: None,
+    }));
+    let graph = serde_json::to_value(GraphReport::new(&graphs, None)?)?;
+    assert_eq!(graph["version"], REPORT_VERSION);
+    assert_eq!(keys(&graph), ["nodes", "version"]);
+    assert_eq!(
+        keys(&graph["nodes"][0]),
+        [
+            "chain",
+            "id",
+            "name",
+            "namespaces",
+            "relations",
+            "root",
+            "unit"
+        ]
+    );
+    let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+    let check = serde_json::to_value(CheckReport::new(&graphs, ni, &ctx)?)?;
+    assert_eq!(
+        keys(&check),
+        ["id", "name", "namespaces", "pass", "version"]
+    );
+    assert_eq!(check["pass"], true);
+    let ps = serde_json::to_value(PsReport {
+        version: REPORT_VERSION,
+        id: 0,
+        name: None,
+        by: "pid",
+        processes: vec![ProcessReport {
+            holder: Holder {
+                pid: 1,
+                comm: "init".to_owned(),
+                uid: 0,
+            },
+            cmdline: "/sbin/init".to_owned(),
+        }],
+    })?;
+    assert_eq!(keys(&ps), ["by", "id", "name", "processes", "version"]);
+    assert_eq!(keys(&ps["processes"][0]), ["cmdline", "comm", "pid", "uid"]);
+    Ok(())
+}
@@ -9,41 +9,57 @@ use std::{
         OpenOptions,
     },
     io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
     os::{
-        fd::AsRawFd,
-        unix::{ffi::OsStrExt, net::UnixStream},
+        fd::{AsRawFd, FromRawFd},
+        unix::{ffi::OsStrExt, fs::chown, net::UnixStream},
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::exit,
     sync::mpsc::sync_channel,
+    time::Duration,
 };
 
-use anyhow::{bail, ensure};
+use anyhow::{anyhow, bail, ensure};
 use daggy::NodeIndex;
 use id_alloc::NetRange;
 use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use libc::{pid_t, stat, syscall, uid_t};
+use linux_raw_sys::ioctl::NS_GET_OWNER_UID;
 use netlink_ops::{
-    netlink::{nl_ctx, NLDriver, NLHandle, VPairKey, VethConn},
+    netlink::{nl_ctx, LinkKey, NLDriver, NLHandle, VPairKey, VethConn},
+    rtnetlink::{
+        netlink_proto::{new_connection_from_socket, NetlinkCodec},
+        netlink_sys::{protocols::NETLINK_ROUTE, Socket, TokioSocket},
+        Handle,
+    },
     state::{Existence, ExpCollection},
 };
+use serde::{Deserialize, Serialize};
 use tracing::{info, warn};
 use uzers::os::unix::UserExt;
 use zbus::Connection;
 
 use super::*;
 use crate::{
+    audit,
     data::*,
     paths::{Binds, PathState, Paths},
+    plan::{Action, Recorder},
 };
 
 use nix::{
     mount::{mount, umount, umount2, MntFlags, MsFlags},
+    poll::{poll, PollFd, PollFlags},
     sched::{setns, unshare, CloneFlags},
-    sys::{signal::kill, stat::fstat, wait::waitpid},
+    sys::{
+        signal::{kill, Signal},
+        stat::fstat,
+        wait::{waitpid, WaitStatus},
+    },
     unistd::{
-        fork, getresuid, getuid, initgroups, seteuid, setgroups, setresgid, setresuid, setuid,
-        ForkResult, Gid, Pid, Uid,
+        fork, geteuid, getresgid, getresuid, getuid, initgroups, pause, seteuid, setgroups,
+        setresgid, setresuid, setsid, setuid, ForkResult, Gid, Pid, Uid,
     },
 };
 
@@ -56,7 +72,13 @@ use nix::{
     NixPath,
 };
 
-fn mount_single(mut pid: PidPath, binds: &Binds, really: bool, name: &str) -> Result<ExactNS> {
+/// Records the bind instead, when planning
+fn mount_single(
+    mut pid: PidPath,
+    binds: &Binds,
+    plan: Option<&Recorder>,
+    name: &str,
+) -> Result<ExactNS> {
     // let name = K::NAME;
     // pid = pid.to_n();
     let path: PathBuf = ["/proc", pid.to_str().as_ref(), "ns", name]
@@ -65,7 +87,13 @@ fn mount_single(mut pid: PidPath, binds: &Binds, really: bool, name: &str) -> Re
     let stat = nix::sys::stat::stat(&path)?;
     let bindat = binds.ns(name);
 
-    if really {
+    let act = Action::Mount {
+        src: path.clone(),
+        dst: bindat.clone(),
+    };
+    if let Some(rec) = plan {
+        rec.push(act);
+    } else {
         let _ = File::create(&bindat)?;
         mount(
             Some(&path),
@@ -74,19 +102,21 @@ fn mount_single(mut pid: PidPath, binds: &Binds, really: bool, name: &str) -> Re
             MsFlags::MS_BIND,
             None::<&str>,
         )?;
+        audit::record(&act);
     }
 
     Ok(ExactNS {
         source: NSSource::Path(bindat),
         unique: stat.into(),
+        fp: None,
     })
 }
 
 #[public]
 impl<K: NSTrait> NSSlot<ExactNS, K> {
-    fn mount(mut pid: PidPath, binds: &Binds, really: bool) -> Result<Self> {
+    fn mount(mut pid: PidPath, binds: &Binds, plan: Option<&Recorder>) -> Result<Self> {
         let name = K::NAME;
-        let e = mount_single(pid, binds, really, name)?;
+        let e = mount_single(pid, binds, plan, name)?;
         Ok(NSSlot::Provided(e, Default::default()))
     }
     fn source(mut self, replace: NSSource) -> Self {
@@ -103,16 +133,17 @@ impl<K: NSTrait> NSSlot<ExactNS, K> {
 
 #[public]
 impl NSGroup<ExactNS> {
-    /// Returns the mounted procNSes from /proc/mountinfo
+    /// Returns the mounted procNSes from /proc/mountinfo, by node
     /// Remember to enter userns (usually) or mounts wont be visible
-    fn mounted(paths: &PathState, id: NodeI, root: bool) -> Result<HashMap<Ix, NSGroup<ExactNS>>> {
+    fn mounted(paths: &PathState, root: bool) -> Result<HashMap<Ix, NSGroup<ExactNS>>> {
         let mut map = HashMap::new();
-        let binds = paths.mount(id, root)?.0;
+        let binds = paths.private(root)?;
         let it = proc_mounts::MountIter::new()?;
         let maps = nstypes();
         for m in it {
             let m = m?;
-            let path = m.source;
+            // Source of nsfs mounts is "nsfs"
+            let path = m.dest;
             if m.fstype == "nsfs" && path.starts_with(&binds) {
                 let ns = path.file_name().unwrap().to_string_lossy();
                 let id = path.parent().unwrap().file_name().unwrap();
@@ -129,11 +160,24 @@ impl NSGroup<ExactNS> {
         Ok(map)
     }
     /// Umount all namespaces and remove the dir
-    fn umount(id: NodeI, paths: &PathState, root: bool) -> Result<()> {
-        let binds = paths.mount(id, root)?.0;
+    fn umount(id: NodeI, paths: &PathState, root: bool, plan: Option<&Recorder>) -> Result<()> {
+        let binds = if plan.is_some() {
+            let binds = paths.mount_path(id, root)?;
+            if !binds.exists() {
+                return Ok(());
+            }
+            binds
+        } else {
+            paths.mount(id, root)?.0
+        };
         for e in std::fs::read_dir(&binds)? {
             let e = e?;
             let p = e.path();
+            let act = Action::Umount(p.clone());
+            if let Some(rec) = plan {
+                rec.push(act);
+                continue;
+            }
             info!("umount {:?}", &p);
             let rx = umount2(&p, MntFlags::MNT_DETACH | MntFlags::MNT_FORCE);
             match rx {
@@ -148,7 +192,7 @@ impl NSGroup<ExactNS> {
                         k => return Err(k.into()),
                     }
                 }
-                _ => (),
+                _ => audit::record(&act),
             }
         }
         Ok(())
@@ -159,7 +203,7 @@ impl NSGroup<ExactNS> {
             if dir.file_type()?.is_dir() {
                 let pa: Result<u32, _> = dir.file_name().to_string_lossy().parse();
                 if let Ok(id) = pa {
-                    Self::umount(id.into(), paths, root)?;
+                    Self::umount(id.into(), paths, root, None)?;
                 }
             }
         }
@@ -179,7 +223,7 @@ fn mount_self() -> Result<()> {
     let path = PathState::default(1000)?;
     let path: Paths = path.into();
     dbg!(path.clone());
-    let mounted = mount_ns_by_pid(PidPath::Selfproc, &path, 3.into(), true, false)?;
+    let mounted = mount_ns_by_pid(PidPath::Selfproc, &path, 3.into(), None, false)?;
     dbg!(mounted);
 
     Ok(())
@@ -220,6 +264,13 @@ pub trait NSEnter {
 
 pub struct UserNS<'p>(pub &'p PathState);
 
+/// Recorded in user_nomnt.pid, for the process holding the user and mnt NS in place of mounts
+#[derive(Serialize, Deserialize, Debug)]
+struct KeptUserNS {
+    pid: pid_t,
+    start: u64,
+}
+
 #[test]
 fn sockpairfork() -> Result<()> {
     let (mut sa, mut sb) = UnixStream::pair()?;
@@ -241,6 +292,9 @@ fn sockpairfork() -> Result<()> {
 #[public]
 impl<'p> UserNS<'p> {
     fn exist(&self) -> Result<bool> {
+        if self.kept()?.is_some() {
+            return Ok(true);
+        }
         let mut f = OpenOptions::new().read(true).open("/proc/self/mountinfo")?;
         let read = BufReader::new(&mut f);
         let (u, p) = self.paths()?;
@@ -281,6 +335,10 @@ impl<'p> UserNS<'p> {
 
         match unsafe { fork() }? {
             ForkResult::Child => {
+                // newgidmap checks the gid of the target too
+                let g = Gid::from_raw(primary_gid(owner)?);
+                setgroups(&[g])?;
+                setresgid(g, g, g)?;
                 let u = Uid::from_raw(owner);
                 setresuid(u, u, u)?;
                 // After setting EUID, flag dumpable is changed, and perms in /proc get changed too
@@ -303,15 +361,19 @@ impl<'p> UserNS<'p> {
                 let mut k: [u8; 1] = [0];
 
                 sb.read_exact(&mut k)?; // unshared
-                let mut f = OpenOptions::new()
-                    .write(true)
-                    .open(format!("/proc/{child}/uid_map"))?;
-                // f.write_all(format!("{u} {u} 1").as_bytes())?; // map uid (in user ns) to uid (outside) for range 1
-                f.write_all(format!("0 0 4294967295").as_bytes())?;
-                let mut f = OpenOptions::new()
-                    .write(true)
-                    .open(format!("/proc/{child}/gid_map"))?;
-                f.write_all(format!("0 0 4294967295").as_bytes())?;
+                let gid = primary_gid(owner)?;
+                // Root stays unmapped. It enters as the owner, see [enter_as_owner]
+                let (uidmap, gidmap) = match SubIDs::of(owner)? {
+                    Some(sub) => (
+                        idmap_ranges(owner, owner, &sub.uids),
+                        idmap_ranges(gid, gid, &sub.gids),
+                    ),
+                    None => {
+                        warn!("No subordinate IDs of {owner}. Mapping only the owner");
+                        (vec![(owner, owner, 1)], vec![(gid, gid, 1)])
+                    }
+                };
+                newidmap_as(owner, child.as_raw(), &uidmap, &gidmap)?;
 
                 mount(
                     Some(&puser),
@@ -334,7 +396,79 @@ impl<'p> UserNS<'p> {
 
         Ok(())
     }
+    /// Starts a process holding the user and mnt NS, instead of mounting them. Needs no root.
+    fn init_kept(&self, owner: uid_t) -> Result<()> {
+        let (mut sa, mut sb) = UnixStream::pair()?;
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(sb);
+                let mut keep = || -> Result<()> {
+                    setsid()?;
+                    if getuid().is_root() {
+                        let g = Gid::from_raw(primary_gid(owner)?);
+                        setresgid(g, g, g)?;
+                        let u = Uid::from_raw(owner);
+                        setresuid(u, u, u)?;
+                    }
+                    unshare_user_standalone(owner, None, true, owner, None)?;
+                    sa.write_all(&[0])?; // unshared
+                    Ok(())
+                };
+                if let Err(e) = keep() {
+                    log::error!("{}", e);
+                    exit(1);
+                }
+                loop {
+                    pause();
+                }
+            }
+            ForkResult::Parent { child } => {
+                drop(sa);
+                let mut k: [u8; 1] = [0];
+                sb.read_exact(&mut k)
+                    .map_err(|_| anyhow!("Keeper of the UserNS failed"))?;
+                let pid = child.as_raw();
+                // The start time is of the keeper only if it still runs after the read
+                let pidfd = unsafe { pidfd::PidFd::open(pid, 0) }?;
+                let start = proc_start(pid)?;
+                let mut polls = [PollFd::new(&pidfd, PollFlags::POLLIN)];
+                if poll(&mut polls, 0)? > 0 {
+                    bail!("Keeper of the UserNS exited");
+                }
+                let kept = KeptUserNS { pid, start };
+                atomic_write(
+                    &self.0.user_nomnt(),
+                    &serde_json::to_vec(&kept)?,
+                    Some(owner),
+                )?;
+                log::info!("UserNS inited, kept by {}", pid);
+            }
+        }
+        Ok(())
+    }
+    /// The keeper of the NSes and its start time, if it still runs
+    fn kept(&self) -> Result<Option<(pid_t, u64)>> {
+        let kept: KeptUserNS = match std::fs::read(self.0.user_nomnt()) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if unsafe { pidfd::PidFd::open(kept.pid, 0) }.is_err() {
+            return Ok(None);
+        }
+        // The PID may have been reused
+        match proc_start(kept.pid) {
+            Ok(s) if s == kept.start => Ok(Some((kept.pid, kept.start))),
+            _ => Ok(None),
+        }
+    }
     fn deinit(&self) -> Result<()> {
+        if let Some((pid, _)) = self.kept()? {
+            kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+            remove_file(self.0.user_nomnt())?;
+            log::info!("UserNS deinited, stopped {}", pid);
+            return Ok(());
+        }
         let (user, mnt) = self.paths()?;
         let private = mnt.parent().unwrap();
         if private.exists() {
@@ -375,6 +509,22 @@ impl<'p> UserNS<'p> {
     }
     /// Generate a [ProcNS]
     fn procns(&self) -> Result<NSGroup<ExactNS>> {
+        if let Some((pid, start)) = self.kept()? {
+            info!("expect userns, mntns of keeper {}", pid);
+            let fp = Some(Fingerprint {
+                start: Some(start),
+                cookie: None,
+            });
+            let mut user = ExactNS::from_source(pid)?;
+            user.fp = fp;
+            let mut mnt = ExactNS::from_source(pid)?;
+            mnt.fp = fp;
+            return Ok(NSGroup {
+                user: NSSlot::Provided(user, Default::default()),
+                mnt: NSSlot::Provided(mnt, Default::default()),
+                ..Default::default()
+            });
+        }
         let (user, mnt) = self.paths()?;
         info!("expect userns={:?}, mntns={:?}", &user, &mnt);
         Ok(NSGroup {
@@ -410,6 +560,22 @@ fn test_userns() -> Result<()> {
     Ok(())
 }
 
+/// Root enters the node of a user, as that user. Run as root
+#[test]
+fn userns_enter() -> Result<()> {
+    let path = PathState::default(1000)?;
+    let usern = UserNS(&path);
+    if !usern.exist()? {
+        usern.init(1000)?;
+    }
+    let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+    usern.procns()?.enter(&ctx)?;
+    // Mapped, rather than the overflow uid
+    assert_eq!(geteuid().as_raw(), 1000);
+
+    Ok(())
+}
+
 #[test]
 fn userns_deinit() -> Result<()> {
     let path = PathState::default(1000)?;
@@ -449,6 +615,32 @@ unsafe fn mount_setattr(
     Errno::result(k).map(drop)
 }
 
+/// Replaces the file at once. Readers see either the old or the new content, even after a crash.
+pub fn atomic_write(path: &Path, contents: &[u8], owner: Option<u32>) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("{:?} has no file name", path))?;
+    let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    let write = || {
+        let mut f = File::create(&tmp)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+        if let Some(uid) = owner {
+            chown(&tmp, Some(uid), None)?;
+        }
+        std::fs::rename(&tmp, path)?;
+        aok!()
+    };
+    if let Err(e) = write() {
+        let _ = remove_file(&tmp);
+        return Err(e);
+    }
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
 pub fn check_capsys() -> Result<()> {
     let caps = capctl::CapState::get_current().unwrap();
     if !caps.effective.has(capctl::Cap::SYS_ADMIN) {
@@ -532,8 +724,178 @@ pub fn what_uid(uid: Option<u32>, allow_root: bool) -> Result<u32> {
     }
 }
 
-/// Unshare the process into a separate userns, rootless
-/// Map one single uid, and gid.
+/// Subordinate ranges of a user, as (start, count)
+#[public]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SubIDs {
+    uids: Vec<(u32, u32)>,
+    gids: Vec<(u32, u32)>,
+}
+
+impl SubIDs {
+    /// Ranges of the user in /etc/subuid and /etc/subgid. None if either has none
+    pub fn of(uid: u32) -> Result<Option<Self>> {
+        let name = uzers::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().into_owned());
+        let read = |pa: &str| match std::fs::read_to_string(pa) {
+            Ok(c) => Ok(parse_subid(&c, name.as_deref(), uid)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        };
+        let sub = Self {
+            uids: read("/etc/subuid")?,
+            gids: read("/etc/subgid")?,
+        };
+        Ok((!sub.uids.is_empty() && !sub.gids.is_empty()).then_some(sub))
+    }
+}
+
+/// Lines are `user:start:count`, where user is a name or an ID
+fn parse_subid(content: &str, name: Option<&str>, uid: u32) -> Vec<(u32, u32)> {
+    content
+        .lines()
+        .filter_map(|l| {
+            let mut f = l.trim().split(':');
+            let (who, start, count) = (f.next()?, f.next()?, f.next()?);
+            (Some(who) == name || who == uid.to_string()).then_some(())?;
+            Some((start.parse().ok()?, count.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Maps inner to outer, the ID of the user, then fills the other inner IDs from 0 with the ranges.
+/// Returns (inner, outer, count) triples
+pub fn idmap_ranges(inner: u32, outer: u32, ranges: &[(u32, u32)]) -> Vec<(u32, u32, u32)> {
+    let mut map = vec![(inner, outer, 1)];
+    let mut next = 0;
+    for &(mut start, mut left) in ranges {
+        while left > 0 {
+            if next == inner {
+                next += 1;
+            }
+            let n = if next < inner {
+                left.min(inner - next)
+            } else {
+                left
+            };
+            map.push((next, start, n));
+            next += n;
+            start += n;
+            left -= n;
+        }
+    }
+    map
+}
+
+fn primary_gid(uid: u32) -> Result<u32> {
+    Ok(uzers::get_user_by_uid(uid)
+        .ok_or(anyhow!("user {} does not exist", uid))?
+        .primary_group_id())
+}
+
+/// Maps the IDs into the UserNS of pid, through the setuid helpers, as the unprivileged user
+pub fn newidmap(pid: pid_t, uid: &[(u32, u32, u32)], gid: &[(u32, u32, u32)]) -> Result<()> {
+    for (helper, map) in [("newuidmap", uid), ("newgidmap", gid)] {
+        let args = map.iter().flat_map(|(i, o, n)| [*i, *o, *n]);
+        let st = std::process::Command::new(helper)
+            .arg(pid.to_string())
+            .args(args.map(|a| a.to_string()))
+            .status()
+            .map_err(|e| anyhow!("{} failed to run, {}. Install uidmap or shadow", helper, e))?;
+        ensure!(st.success(), "{} {} failed, {}", helper, pid, st);
+    }
+    Ok(())
+}
+
+/// Runs [newidmap] in a child dropped to the owner, as the helpers only map for the target's owner
+fn newidmap_as(
+    owner: uid_t,
+    pid: pid_t,
+    uid: &[(u32, u32, u32)],
+    gid: &[(u32, u32, u32)],
+) -> Result<()> {
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            let run = || -> Result<()> {
+                let g = Gid::from_raw(primary_gid(owner)?);
+                setgroups(&[g])?;
+                setresgid(g, g, g)?;
+                let u = Uid::from_raw(owner);
+                setresuid(u, u, u)?;
+                newidmap(pid, uid, gid)
+            };
+            exit(match run() {
+                Ok(_) => 0,
+                Err(e) => {
+                    log::error!("{}", e);
+                    1
+                }
+            });
+        }
+        ForkResult::Parent { child } => {
+            let st = waitpid(child, None)?;
+            ensure!(
+                matches!(st, WaitStatus::Exited(_, 0)),
+                "Failed to map IDs into {}, {:?}",
+                pid,
+                st
+            );
+        }
+    }
+    Ok(())
+}
+
+/// UID of the user that created the UserNS
+fn userns_owner(ns: &ExactNS) -> Result<uid_t> {
+    let f = match &ns.source {
+        NSSource::Path(p) => File::open(p)?,
+        NSSource::Pid(p) => File::open(format!("/proc/{}/ns/user", p))?,
+        NSSource::Unavail(_) => bail!("UserNS {:?} can not be opened", ns.unique),
+    };
+    let mut owner: uid_t = 0;
+    Errno::result(unsafe { libc::ioctl(f.as_raw_fd(), NS_GET_OWNER_UID.into(), &mut owner) })?;
+    Ok(owner)
+}
+
+/// Root is not mapped in the UserNSes we make, so it takes the uid of the owner before entering.
+/// The owner gets all caps in the NS, and the saved uid keeps root outside.
+pub fn enter_as_owner(ns: &ExactNS) -> Result<()> {
+    if !geteuid().is_root() {
+        return Ok(());
+    }
+    let owner = userns_owner(ns)?;
+    if owner == 0 {
+        return Ok(());
+    }
+    info!("Enter UserNS as its owner {}", owner);
+    let saved = getresuid()?.saved;
+    let g = Gid::from_raw(primary_gid(owner)?);
+    setresgid(g, g, getresgid()?.saved)?;
+    let u = Uid::from_raw(owner);
+    setresuid(u, u, saved)?;
+    Ok(())
+}
+
+#[test]
+fn subid_map() {
+    let content = "someone:100000:65536\nme:165536:65536\n1000:300000:10\n";
+    let sub = parse_subid(content, Some("me"), 1000);
+    assert_eq!(sub, vec![(165536, 65536), (300000, 10)]);
+    let map = idmap_ranges(1000, 1000, &sub);
+    assert_eq!(
+        map,
+        vec![
+            (1000, 1000, 1),
+            (0, 165536, 1000),
+            (1001, 166536, 64536),
+            (65537, 300000, 10)
+        ]
+    );
+    assert_eq!(idmap_ranges(0, 1000, &[(100000, 5)])[1], (1, 100000, 5));
+}
+
+/// Unshare the process into a separate userns, rootless.
+/// Map the uid and gid, and the subordinate IDs of the user if there are any.
+/// Otherwise only the one uid, and gid, with setgroups denied.
 pub fn unshare_user_standalone(
     uid: u32,
     gid: Option<u32>,
@@ -562,6 +924,42 @@ pub fn unshare_user_standalone(
         user.primary_group_id()
     };
 
+    if let Some(sub) = SubIDs::of(uid_out)? {
+        let uidmap = idmap_ranges(uid, uid_out, &sub.uids);
+        let gidmap = idmap_ranges(gid, gid_out, &sub.gids);
+        info!("uidmap: {:?}, gidmap: {:?}", &uidmap, &gidmap);
+        // The helper must stay in the parent UserNS, so it forks before the unshare
+        let parent = nix::unistd::getpid().as_raw();
+        let (mut sa, mut sb) = UnixStream::pair()?;
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                let mut k: [u8; 1] = [0];
+                sa.read_exact(&mut k)?; // unshared
+                let code = match newidmap(parent, &uidmap, &gidmap) {
+                    Ok(_) => 0,
+                    Err(e) => {
+                        log::error!("{}", e);
+                        1
+                    }
+                };
+                sa.write_all(&[code])?;
+                exit(0);
+            }
+            ForkResult::Parent { child } => {
+                unshare(flg)?;
+                sb.write_all(&[0])?;
+                let mut k: [u8; 1] = [0];
+                sb.read_exact(&mut k)?;
+                waitpid(child, None)?;
+                ensure!(k[0] == 0, "Failed to map subordinate IDs");
+            }
+        }
+        return Ok(NSGroup {
+            user: NSSlot::from_source(PidPath::Selfproc)?,
+            ..Default::default()
+        });
+    }
+
     unshare(flg)?;
     let mut f = OpenOptions::new()
         .write(true)
@@ -596,24 +994,113 @@ pub async fn systemd_connection(root: bool) -> Result<Connection> {
     Ok(z)
 }
 
+/// Netlink handle in the NetNS of `ns`.
+/// The socket is opened by entering the NetNS on this thread, which then returns.
+pub async fn nl_in_ns(ns: &ExactNS) -> Result<NLHandle> {
+    if matches!(ns.source, NSSource::Unavail(_)) {
+        bail!("NetNS {} can not be entered", ns);
+    }
+    let curr = File::open("/proc/self/ns/net")?;
+    ns.enter(CloneFlags::CLONE_NEWNET)?;
+    let sock = Socket::new(NETLINK_ROUTE);
+    setns(curr, CloneFlags::CLONE_NEWNET)?;
+    let sock = sock?;
+    let fd = nix::unistd::dup(sock.as_raw_fd())?;
+    let (conn, handle, _) =
+        new_connection_from_socket::<_, _, NetlinkCodec>(unsafe { TokioSocket::from_raw_fd(fd) });
+    tokio::spawn(conn);
+    Ok(NLHandle::new(Handle::new(handle), ns.clone()))
+}
+
+/// Processes whose NS of the kind, ex. pid or net, is `ns`.
+/// Processes we may not inspect are skipped.
+pub fn ns_members(ns: &UniqueFile, kind: &str) -> Result<Vec<pid_t>> {
+    let mut pids = Vec::new();
+    for en in read_dir("/proc")? {
+        let en = en?;
+        let pid: pid_t = match en.file_name().to_string_lossy().parse() {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        if let Ok(st) = nix::sys::stat::stat(&en.path().join("ns").join(kind)) {
+            if UniqueFile::from(st) == *ns {
+                pids.push(pid);
+            }
+        }
+    }
+    Ok(pids)
+}
+
+/// Command line of the process, or its name if it has none, as with kernel threads
+pub fn cmdline(pid: pid_t) -> String {
+    let args = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args: Vec<_> = args
+        .split(|b| *b == 0)
+        .filter(|a| !a.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+    if args.is_empty() {
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+        format!("[{}]", comm.trim_end())
+    } else {
+        args.join(" ")
+    }
+}
+
+/// Connects to the address from the NetNS, on a thread that enters it
+pub fn reachable_from(ns: &ExactNS, addr: &str) -> Result<()> {
+    let (ns, addr) = (ns.clone(), addr.to_owned());
+    std::thread::spawn(move || {
+        if matches!(ns.source, NSSource::Unavail(_)) {
+            let curr = ExactNS::from_source((PidPath::Selfproc, "net"))?;
+            ensure!(curr.unique == ns.unique, "NetNS {} can not be entered", ns);
+        } else {
+            ns.enter(CloneFlags::CLONE_NEWNET)?;
+        }
+        let sa = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(anyhow!("{} resolves to no address", addr))?;
+        TcpStream::connect_timeout(&sa, Duration::from_secs(3))?;
+        aok!()
+    })
+    .join()
+    .map_err(|_| anyhow!("Reachability check panicked"))?
+}
+
 pub async fn connect_ns_veth(
     nl_ch: NLHandle,
     nl: NLHandle,
-    mut veth_key: Option<VPairKey>,
+    veth_key: Option<VPairKey>,
 ) -> Result<VethConn> {
     let mut nl_ch = NLDriver::new(nl_ch);
     let mut nl = NLDriver::new(nl);
-    log::info!("Fetch netlink");
-    nl.fill().await?;
     log::info!("Fetch netlink (child process)");
     nl_ch.fill().await?;
-    log::info!("Netlink fetched");
-    let mut addrset: HashSet<IpNetwork> = HashSet::default(); // find unused subnet
+    let act = Action::LinkUp("lo".to_owned());
     {
         nl_ctx!(link, conn, nl_ch);
         conn.set_up(link.map.get_mut(&"lo".parse()?).unwrap().exist_mut()?)
             .await?;
     }
+    audit::record(&act);
+    let vc = alloc_veth(&mut nl, veth_key).await?;
+    // let edge = graphs.data.add_edge(src, out, None);
+    vc.apply(&mut nl_ch, &mut nl).await?;
+    let mut nl_ch = NLDriver::new(nl_ch.conn);
+    let mut nl = NLDriver::new(nl.conn);
+    nl_ch.fill().await?;
+    nl.fill().await?;
+    vc.apply_addr_up(&mut nl_ch, &mut nl).await?;
+    audit::record(&veth_action(&vc));
+    Ok(vc)
+}
+
+/// Picks a subnet unused by the links of nl. Nothing is changed
+pub async fn alloc_veth(nl: &mut NLDriver, mut veth_key: Option<VPairKey>) -> Result<VethConn> {
+    log::info!("Fetch netlink");
+    nl.fill().await?;
+    let mut addrset: HashSet<IpNetwork> = HashSet::default(); // find unused subnet
     {
         nl_ctx!(link, conn, nl);
         for (k, ex) in link.map {
@@ -642,7 +1129,7 @@ pub async fn connect_ns_veth(
     if veth_key.is_none() {
         veth_key = Some(format!("nsproxy{}", num).try_into()?);
     }
-    let vc = VethConn {
+    Ok(VethConn {
         subnet_veth: net4.into(),
         subnet6_veth: net6.into(),
         ip_va: Ipv4Network::new(net4.nth(0).unwrap(), p4)?.into(),
@@ -650,13 +1137,36 @@ pub async fn connect_ns_veth(
         ip6_va: n6net[0],
         ip6_vb: n6net[1],
         key: veth_key.unwrap(),
-    };
-    // let edge = graphs.data.add_edge(src, out, None);
-    vc.apply(&mut nl_ch, &mut nl).await?;
-    let mut nl_ch = NLDriver::new(nl_ch.conn);
-    let mut nl = NLDriver::new(nl.conn);
-    nl_ch.fill().await?;
-    nl.fill().await?;
-    vc.apply_addr_up(&mut nl_ch, &mut nl).await?;
-    Ok(vc)
+    })
+}
+
+pub fn veth_action(vc: &VethConn) -> Action {
+    Action::Veth {
+        key: vc.key.to_string(),
+        ip_va: vc.ip_va.to_string(),
+        ip_vb: vc.ip_vb.to_string(),
+        ip6_va: vc.ip6_va.to_string(),
+        ip6_vb: vc.ip6_vb.to_string(),
+    }
+}
+
+/// Removal of links. [NLDriver] performs it, and a [Recorder] lists it when planning
+pub trait LinkM {
+    async fn remove(&mut self, link: &LinkKey) -> Result<()>;
+}
+
+impl LinkM for NLDriver {
+    async fn remove(&mut self, link: &LinkKey) -> Result<()> {
+        info!("Remove {:?}", link);
+        self.remove_link(link).await?;
+        audit::record(&Action::RemoveLink(format!("{:?}", link)));
+        Ok(())
+    }
+}
+
+impl LinkM for Recorder {
+    async fn remove(&mut self, link: &LinkKey) -> Result<()> {
+        self.push(Action::RemoveLink(format!("{:?}", link)));
+        Ok(())
+    }
 }
@@ -2,7 +2,7 @@
 //! https://www.freedesktop.org/software/systemd/man/systemctl.html
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env::{current_dir, current_exe},
     fmt::Debug,
     fs::{create_dir_all, remove_file},
@@ -22,12 +22,14 @@ use zbus::Address;
 
 use super::*;
 use crate::{
-    data::{EdgeI, FDRecver, Ix, NSGroup, NodeI, ObjectNode, PassFD, Relation},
+    data::{EdgeI, FDRecver, Graphs, Ix, NSGroup, NodeI, ObjectNode, PassFD, Relation},
     managed::{
         IRelation, Indexed, ItemAction, ItemCreate, ItemRM, MItem, NDeps, NodeIndexed, NodeWDeps,
-        ServiceM, Socks2TUN,
+        ServiceM, Socks2TUN, UnitRecver,
     },
     paths::PathState,
+    plan::{Action, Planned, Recorder},
+    recover::{parse_exec, FoundRecver, FoundTUN},
 };
 
 pub mod service;
@@ -39,12 +41,18 @@ pub struct Systemd {
     self_path: PathBuf,
     pub conn: Option<zbus::Connection>,
     root: bool,
+    /// Set for the stand-in of planning
+    plan: Option<Recorder>,
 }
 
 impl<'b> MItem for Socks2TUN<'b> {
     type Serv = Systemd;
 }
 
+impl<'b> MItem for UnitRecver<'b> {
+    type Serv = Systemd;
+}
+
 impl<'n, 'd> MItem for NodeWDeps<'n, 'd> {
     type Serv = Systemd;
 }
@@ -62,26 +70,11 @@ impl<'k> MItem for NDeps<'k> {
 
 impl<'k> ItemRM for NodeIndexed<'k> {
     async fn remove(&self, serv: &Self::Serv) -> Result<()> {
-        remove_file_lenient(&serv.systemd_unit.join(self.service()?))?;
+        serv.remove_file_lenient(&serv.systemd_unit.join(self.service()?))?;
         Ok(())
     }
 }
 
-pub fn remove_file_lenient(path: impl AsRef<Path> + Debug) -> Result<()> {
-    info!("trying to remove file {:?}", &path);
-    match remove_file(&path) {
-        Err(err) => {
-            if err.kind() == ErrorKind::NotFound {
-                log::warn!("File {:?} not found", path);
-                Ok(())
-            } else {
-                Err(err.into())
-            }
-        }
-        _ => Ok(()),
-    }
-}
-
 pub fn match_root(serv: &Systemd, root: bool) -> Result<()> {
     if serv.root != root {
         bail!(
@@ -100,13 +93,13 @@ impl<'k> ItemAction for NodeIndexed<'k> {
     ) -> Result<()> {
         let n = self.service()?;
         log::info!("(Re)start unit {n}");
-        ctx.restart_unit(&n, Replace).await?;
+        serv.restart_unit(ctx, &n).await?;
         Ok(())
     }
     async fn stop(&self, serv: &Self::Serv, ctx: &<Self::Serv as ServiceM>::Ctx<'_>) -> Result<()> {
         let n = self.service()?;
         log::info!("Stop unit {n}");
-        ctx.stop_unit(&n, Replace).await?;
+        serv.stop_unit(ctx, &n).await?;
         Ok(())
     }
 }
@@ -122,6 +115,7 @@ pub fn units(ve: &NDeps<'_>) -> Result<HashSet<String>> {
         match re {
             FDRecver::Systemd(se) => {
                 units.insert(se.to_owned());
+                units.insert(UnitRecver::new(se, edge.id).sockunit()?);
             }
             FDRecver::TUN2Proxy(pa) => {
                 let socks2 = Socks2TUN::new(&pa, edge.id)?;
@@ -142,14 +136,14 @@ impl<'k> ItemAction for NDeps<'k> {
     ) -> Result<()> {
         let units = units(self)?;
         for s in units {
-            ctx.restart_unit(&s, Replace).await?;
+            serv.restart_unit(ctx, &s).await?;
         }
         Ok(())
     }
     async fn stop(&self, serv: &Self::Serv, ctx: &<Self::Serv as ServiceM>::Ctx<'_>) -> Result<()> {
         let units = units(self)?;
         for s in units {
-            ctx.stop_unit(&s, Replace).await?;
+            serv.stop_unit(ctx, &s).await?;
         }
         Ok(())
     }
@@ -187,6 +181,9 @@ impl<'n, 'd> ItemRM for NodeWDeps<'n, 'd> {
                                 let socks2 = Socks2TUN::new(path, dep.edge.id)?;
                                 socks2.remove(serv).await?;
                             }
+                            FDRecver::Systemd(unit) => {
+                                UnitRecver::new(unit, dep.edge.id).remove(serv).await?;
+                            }
                             _ => (),
                         }
                     }
@@ -232,7 +229,7 @@ impl<'n, 'd> ItemCreate for NodeWDeps<'n, 'd> {
             );
         }
         let servpath = serv.systemd_unit.join(&servname);
-        service.write_to_file(&servpath)?;
+        serv.write_unit(&service, &servpath)?;
         log::info!(
             "Wrote probe unit to {:?}, with dependencies {:?}",
             &servpath,
@@ -259,7 +256,7 @@ impl<'b> ItemCreate for Socks2TUN<'b> {
         socket
             .with_section(Some("Socket"))
             .set("ListenStream", path_to_str(&sfile)?);
-        socket.write_to_file(&sunit)?;
+        serv.write_unit(&socket, &sunit)?;
 
         let mut service = ini::Ini::new();
         service
@@ -267,6 +264,15 @@ impl<'b> ItemCreate for Socks2TUN<'b> {
             .set("Description", format!("TUN2Proxy of {:?}", &stem))
             .set("Requires", &selfsock)
             .set("After", &selfsock);
+        if let Some(up) = &self.upstream {
+            // Runs in the NetNS of the out node, which routes through the hop before it.
+            // Restarts of that hop cascade here through the probe.
+            service
+                .with_section(Some("Unit"))
+                .set("Wants", up)
+                .set("PartOf", up)
+                .set("After", format!("{} {}", &selfsock, up));
+        }
         assert!(self.confpath.exists());
         let mut servsec = service.with_section(Some("Service"));
         let sec = servsec
@@ -291,7 +297,7 @@ impl<'b> ItemCreate for Socks2TUN<'b> {
 
         let servname = self.service()?;
         let servpath = serv.systemd_unit.join(&servname);
-        service.write_to_file(&servpath)?;
+        serv.write_unit(&service, &servpath)?;
         log::info!("Wrote Tun2proxy unit to {:?}", &servpath);
         Ok(Relation::SendTUN(PassFD {
             creation: data::TUNC {
@@ -312,20 +318,66 @@ impl<'b> ItemAction for Socks2TUN<'b> {
         ctx: &<Self::Serv as ServiceM>::Ctx<'_>,
     ) -> Result<()> {
         let servname = self.service()?;
-        ctx.restart_unit(&servname, Replace).await?;
+        serv.restart_unit(ctx, &servname).await?;
         Ok(())
     }
     async fn stop(&self, serv: &Self::Serv, ctx: &<Self::Serv as ServiceM>::Ctx<'_>) -> Result<()> {
         let servname = self.service()?;
-        ctx.stop_unit(&servname, Replace).await?;
+        serv.stop_unit(ctx, &servname).await?;
         Ok(())
     }
 }
 
 impl<'b> ItemRM for Socks2TUN<'b> {
     async fn remove(&self, serv: &Self::Serv) -> Result<()> {
-        remove_file_lenient(serv.systemd_unit.join(self.service()?))?;
-        remove_file_lenient(serv.systemd_unit.join(self.sockunit()?))?;
+        serv.remove_file_lenient(serv.systemd_unit.join(self.service()?))?;
+        serv.remove_file_lenient(serv.systemd_unit.join(self.sockunit()?))?;
+        Ok(())
+    }
+}
+
+impl<'b> ItemCreate for UnitRecver<'b> {
+    type Param = ();
+    /// Where the probe connects
+    type Created = PathBuf;
+    async fn write(&self, _param: Self::Param, serv: &Self::Serv) -> Result<Self::Created> {
+        let sfile = serv.tun2proxy_socks.join(self.sockf()?);
+        let sunit = serv.systemd_unit.join(self.sockunit()?);
+        let mut socket = ini::Ini::new();
+        socket
+            .with_section(Some("Unit"))
+            .set("Description", format!("FD Receiver {:?}", self.service));
+        socket
+            .with_section(Some("Socket"))
+            .set("ListenStream", path_to_str(&sfile)?)
+            .set("Service", self.service);
+        serv.write_unit(&socket, &sunit)?;
+        log::info!("Wrote socket unit to {:?}, for {}", &sunit, self.service);
+        Ok(sfile)
+    }
+}
+
+impl<'b> ItemAction for UnitRecver<'b> {
+    async fn restart(
+        &self,
+        serv: &Self::Serv,
+        ctx: &<Self::Serv as ServiceM>::Ctx<'_>,
+    ) -> Result<()> {
+        let name = self.sockunit()?;
+        serv.restart_unit(ctx, &name).await?;
+        Ok(())
+    }
+    /// The service is the user's, and left running
+    async fn stop(&self, serv: &Self::Serv, ctx: &<Self::Serv as ServiceM>::Ctx<'_>) -> Result<()> {
+        let name = self.sockunit()?;
+        serv.stop_unit(ctx, &name).await?;
+        Ok(())
+    }
+}
+
+impl<'b> ItemRM for UnitRecver<'b> {
+    async fn remove(&self, serv: &Self::Serv) -> Result<()> {
+        serv.remove_file_lenient(serv.systemd_unit.join(self.sockunit()?))?;
         Ok(())
     }
 }
@@ -333,6 +385,20 @@ impl<'b> ItemRM for Socks2TUN<'b> {
 #[public]
 impl Systemd {
     fn new(paths: &PathState, conn: Option<zbus::Connection>, root: bool) -> Result<Self> {
+        let serv = Self::with(paths, conn, root, None)?;
+        create_dir_all(&serv.systemd_unit)?;
+        Ok(serv)
+    }
+    /// Stand-in that records what it would write, restart and stop. It needs no connection
+    fn planned(paths: &PathState, root: bool, rec: Recorder) -> Result<Self> {
+        Self::with(paths, None, root, Some(rec))
+    }
+    fn with(
+        paths: &PathState,
+        conn: Option<zbus::Connection>,
+        root: bool,
+        plan: Option<Recorder>,
+    ) -> Result<Self> {
         let path = paths.tun2proxy();
         let base = directories::BaseDirs::new().unwrap();
         let systemd_unit = if root {
@@ -340,24 +406,276 @@ impl Systemd {
         } else {
             base.config_local_dir().join("systemd/user")
         };
-        create_dir_all(&systemd_unit)?;
         Ok(Self {
             systemd_unit,
             tun2proxy_socks: path,
             self_path: current_exe()?,
             conn,
             root,
+            plan,
+        })
+    }
+    fn remove_file_lenient(&self, path: impl AsRef<Path> + Debug) -> Result<()> {
+        let act = Action::RemoveFile(path.as_ref().to_owned());
+        if self.record(&act) {
+            return Ok(());
+        }
+        info!("trying to remove file {:?}", &path);
+        match remove_file(&path) {
+            Err(err) => {
+                if err.kind() == ErrorKind::NotFound {
+                    log::warn!("File {:?} not found", path);
+                    Ok(())
+                } else {
+                    Err(err.into())
+                }
+            }
+            _ => {
+                self.done(&act);
+                Ok(())
+            }
+        }
+    }
+    fn write_unit(&self, unit: &ini::Ini, path: &Path) -> Result<()> {
+        let mut contents = Vec::new();
+        unit.write_to(&mut contents)?;
+        let act = Action::write_file(path, &contents);
+        if self.record(&act) {
+            return Ok(());
+        }
+        unit.write_to_file(path)?;
+        self.done(&act);
+        Ok(())
+    }
+    async fn restart_unit(&self, ctx: &Option<ManagerProxy<'_>>, name: &str) -> Result<()> {
+        let act = Action::Restart(name.to_owned());
+        if self.record(&act) {
+            return Ok(());
+        }
+        bus(ctx)?.restart_unit(name, Replace).await?;
+        self.done(&act);
+        Ok(())
+    }
+    async fn stop_unit(&self, ctx: &Option<ManagerProxy<'_>>, name: &str) -> Result<()> {
+        let act = Action::Stop(name.to_owned());
+        if self.record(&act) {
+            return Ok(());
+        }
+        bus(ctx)?.stop_unit(name, Replace).await?;
+        self.done(&act);
+        Ok(())
+    }
+    fn unit_exists(&self, name: &str) -> bool {
+        self.systemd_unit.join(name).exists()
+    }
+    /// tun2proxy relations and receiver units, told from the units we wrote
+    fn found_units(&self) -> Result<(Vec<FoundTUN>, Vec<FoundRecver>)> {
+        // Services required by each probe
+        let mut required = HashMap::new();
+        let mut found = Vec::new();
+        let mut recvers = Vec::new();
+        for e in std::fs::read_dir(&self.systemd_unit)? {
+            let e = e?;
+            let name = e.file_name().to_string_lossy().into_owned();
+            let recver = name
+                .strip_prefix("fdrecv")
+                .and_then(|n| n.strip_suffix(".socket"))
+                .and_then(|n| n.parse::<Ix>().ok());
+            if let Some(edge) = recver {
+                let service = ini::Ini::load_from_file(e.path())
+                    .ok()
+                    .and_then(|u| u.get_from(Some("Socket"), "Service").map(str::to_owned))
+                    .unwrap_or_default();
+                recvers.push((
+                    name,
+                    FoundRecver {
+                        edge,
+                        src: None,
+                        service,
+                    },
+                ));
+                continue;
+            }
+            let Some(stem) = name.strip_suffix(".service") else {
+                continue;
+            };
+            let Ok(unit) = ini::Ini::load_from_file(e.path()) else {
+                continue;
+            };
+            let probe = stem
+                .strip_prefix("probe")
+                .and_then(|i| i.parse::<Ix>().ok());
+            if let Some(id) = probe {
+                let deps = unit.get_from(Some("Unit"), "Requires").unwrap_or_default();
+                for dep in deps.split_whitespace() {
+                    required.insert(dep.to_owned(), id);
+                }
+            } else if let Some((conf, edge)) = unit
+                .get_from(Some("Service"), "ExecStart")
+                .and_then(parse_exec)
+            {
+                // Hops after another run in its node
+                let out = unit.get_from(Some("Unit"), "Wants").and_then(|w| {
+                    w.strip_prefix("probe")?
+                        .strip_suffix(".service")?
+                        .parse()
+                        .ok()
+                });
+                let sock =
+                    ini::Ini::load_from_file(self.systemd_unit.join(stem.to_owned() + ".socket"))?;
+                let listener = sock
+                    .get_from(Some("Socket"), "ListenStream")
+                    .ok_or(anyhow!("{}.socket has no ListenStream", stem))?;
+                found.push((
+                    name,
+                    FoundTUN {
+                        edge,
+                        src: 0,
+                        out,
+                        conf,
+                        listener: listener.into(),
+                    },
+                ));
+            }
+        }
+        let tuns = found
+            .into_iter()
+            .filter_map(|(name, mut t)| match required.get(&name) {
+                Some(src) => {
+                    t.src = *src;
+                    Some(t)
+                }
+                None => {
+                    log::warn!("No probe requires {}", name);
+                    None
+                }
+            })
+            .collect();
+        let recvers = recvers
+            .into_iter()
+            .map(|(name, mut r)| {
+                r.src = required.get(&name).copied();
+                r
+            })
+            .collect();
+        Ok((tuns, recvers))
+    }
+    /// Files of our units whose node or edge is no longer in the graphs
+    fn orphans(&self, graphs: &Graphs) -> Result<Vec<PathBuf>> {
+        let mut expected = HashSet::new();
+        for ni in graphs.data.node_indices() {
+            if let Some(item) = &graphs.data[ni] {
+                expected.insert(NodeIndexed::new(ni, item).service()?);
+            }
+        }
+        for edge in graphs.data.edge_indices() {
+            let Some(rel) = &graphs.data[edge] else {
+                // Edges kept allocated by recover still own their receiver
+                expected.insert(UnitRecver::new("", edge).sockunit()?);
+                continue;
+            };
+            match rel.fd_recver() {
+                Some(FDRecver::TUN2Proxy(conf)) => {
+                    let s2t = Socks2TUN::new(conf, edge)?;
+                    expected.insert(s2t.service()?);
+                    expected.insert(s2t.sockunit()?);
+                }
+                Some(FDRecver::Systemd(unit)) => {
+                    expected.insert(UnitRecver::new(unit, edge).sockunit()?);
+                }
+                _ => (),
+            }
+        }
+        let numbered = |name: &str, prefix: &str, suffix: &str| {
+            name.strip_prefix(prefix)
+                .and_then(|n| n.strip_suffix(suffix))
+                .and_then(|n| n.parse::<Ix>().ok())
+        };
+        // Units of the same names may belong to others, in /etc/systemd/system in particular
+        let mut orphans = Vec::new();
+        for e in std::fs::read_dir(&self.systemd_unit)? {
+            let path = e?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let ours = if let Some(id) = numbered(&name, "probe", ".service") {
+                probe_unit(&path, id)
+            } else if let Some(edge) = numbered(&name, "fdrecv", ".socket") {
+                self.recver_unit(&path, edge)
+            } else if let Some(stem) = name.strip_suffix(".service") {
+                self.tun2proxy_unit(stem)
+            } else if let Some(stem) = name.strip_suffix(".socket") {
+                self.tun2proxy_unit(stem)
+            } else {
+                false
+            };
+            if ours && !expected.contains(name.as_ref()) {
+                orphans.push(path);
+            }
+        }
+        orphans.sort();
+        Ok(orphans)
+    }
+    /// Whether the socket unit listens where our receiver of the edge does
+    fn recver_unit(&self, path: &Path, edge: Ix) -> bool {
+        let sfile = self.tun2proxy_socks.join(format!("fdrecv{}.sock", edge));
+        ini::Ini::load_from_file(path)
+            .ok()
+            .and_then(|u| {
+                u.get_from(Some("Socket"), "ListenStream")
+                    .map(|l| Path::new(l) == sfile)
+            })
+            .unwrap_or_default()
+    }
+    /// Whether the service of the stem runs our tun2proxy
+    fn tun2proxy_unit(&self, stem: &str) -> bool {
+        ini::Ini::load_from_file(self.systemd_unit.join(stem.to_owned() + ".service"))
+            .ok()
+            .and_then(|u| {
+                u.get_from(Some("Service"), "ExecStart")
+                    .and_then(parse_exec)
+            })
+            .is_some()
+    }
+}
+
+/// Whether the service runs our probe of the node
+fn probe_unit(path: &Path, id: Ix) -> bool {
+    ini::Ini::load_from_file(path)
+        .ok()
+        .and_then(|u| {
+            let (exe, rest) = u
+                .get_from(Some("Service"), "ExecStart")?
+                .split_once(" probe ")?;
+            Some(exe.starts_with('"') && rest.trim().parse::<Ix>().ok()? == id)
         })
+        .unwrap_or_default()
+}
+
+impl Planned for Systemd {
+    fn recorder(&self) -> Option<&Recorder> {
+        self.plan.as_ref()
     }
 }
 
+/// None when planning
+fn bus<'a, 'c>(ctx: &'a Option<ManagerProxy<'c>>) -> Result<&'a ManagerProxy<'c>> {
+    ctx.as_ref().ok_or(anyhow!("not connected to systemd"))
+}
+
 impl ServiceM for Systemd {
-    type Ctx<'c> = ManagerProxy<'c>;
+    type Ctx<'c> = Option<ManagerProxy<'c>>;
     async fn ctx<'k>(&'k self) -> Result<Self::Ctx<'k>> {
-        Ok(ManagerProxy::new(self.conn.as_ref().unwrap()).await?)
+        if self.plan.is_some() {
+            return Ok(None);
+        }
+        Ok(Some(ManagerProxy::new(self.conn.as_ref().unwrap()).await?))
     }
     async fn reload(&self, ctx: &Self::Ctx<'_>) -> Result<()> {
-        ctx.reload().await?;
+        let act = Action::Reload;
+        if self.record(&act) {
+            return Ok(());
+        }
+        bus(ctx)?.reload().await?;
+        self.done(&act);
         log::info!("Reloaded");
         Ok(())
     }
@@ -0,0 +1,416 @@
+//! Declarative topology of nodes, kept in a file and reconciled against [Graphs].
+//! Only named nodes are managed. Unnamed nodes, like the one of the root NetNS, are left alone.
+//! Nodes are removed only if apply created them, so those made by hand survive a topology.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail};
+use ipnetwork::IpNetwork;
+use netlink_ops::netlink::{LinkAB, LinkKey, NLDriver};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use crate::{
+    data::{ExactNS, FDRecver, Graphs, NSGroup, NodeI, Relation},
+    managed::Socks2TUN,
+    sys::nl_in_ns,
+    systemd::{Systemd, UnitName},
+};
+
+#[public]
+#[derive(Serialize, Deserialize, Debug)]
+struct Topology {
+    nodes: Vec<NodeSpec>,
+}
+
+/// Mirrors the options of `nsproxy new`. Nodes created from a topology are always mounted.
+#[public]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct NodeSpec {
+    name: String,
+    /// Command to launch in the node. The node persists without it, as it's mounted.
+    #[serde(default)]
+    cmd: Option<String>,
+    /// Name of the node to route through. Defaults to the NetNS nsproxy runs in.
+    #[serde(default)]
+    out: Option<String>,
+    /// Config file for Tun2proxy
+    #[serde(default)]
+    tun2proxy: Option<PathBuf>,
+    #[serde(default)]
+    veth: bool,
+    /// Interface to move into the node
+    #[serde(default)]
+    associated: Option<String>,
+    #[serde(default)]
+    assoc_ip: Option<IpNetwork>,
+    #[serde(default)]
+    uid: Option<u32>,
+    #[serde(default)]
+    userns: bool,
+}
+
+/// Steps that bring the graph to the topology, in the order they should be performed.
+#[derive(Debug)]
+pub enum Step {
+    /// Node is absent
+    Create(NodeSpec),
+    /// Node exists but its out node, veth, moved interface or launch options differ
+    Recreate(NodeI, NodeSpec),
+    /// Replace the TUN relation of the node in place
+    TUN2Proxy(NodeI, NodeSpec),
+    /// The relations match, but units are missing on disk
+    WriteUnits(NodeI),
+    /// Node created by apply is no longer in the topology
+    Remove(NodeI, String),
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Create(spec) => f.write_fmt(format_args!("{} {}", "Create".green(), spec)),
+            Self::Recreate(ix, spec) => f.write_fmt(format_args!(
+                "{} node {}, as {}",
+                "Recreate".yellow(),
+                ix.index(),
+                spec
+            )),
+            Self::TUN2Proxy(ix, spec) => f.write_fmt(format_args!(
+                "{} node {} with tun2proxy {:?}",
+                "Update".yellow(),
+                ix.index(),
+                spec.tun2proxy
+            )),
+            Self::WriteUnits(ix) => f.write_fmt(format_args!(
+                "{} units of node {}",
+                "Rewrite".yellow(),
+                ix.index()
+            )),
+            Self::Remove(ix, name) => f.write_fmt(format_args!(
+                "{} node {} {}",
+                "Remove".red(),
+                ix.index(),
+                name.bright_yellow()
+            )),
+        }
+    }
+}
+
+impl Display for NodeSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}", self.name.bright_yellow()))?;
+        if let Some(out) = &self.out {
+            f.write_fmt(format_args!(" => {}", out))?;
+        }
+        if let Some(t) = &self.tun2proxy {
+            f.write_fmt(format_args!(", tun2proxy {:?}", t))?;
+        }
+        if self.veth {
+            f.write_str(", veth")?;
+        }
+        if let Some(a) = &self.associated {
+            f.write_fmt(format_args!(", moves {}", a))?;
+        }
+        Ok(())
+    }
+}
+
+#[public]
+impl Topology {
+    /// TOML for files ending in .toml, JSON otherwise
+    fn load(path: &Path) -> Result<Self> {
+        let st = std::fs::read_to_string(path)?;
+        let mut topo: Self = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&st)?,
+            _ => serde_json::from_str(&st)?,
+        };
+        let base = path
+            .parent()
+            .ok_or(anyhow!("topology file has no parent directory"))?;
+        let mut names = HashSet::new();
+        for spec in topo.nodes.iter_mut() {
+            if !names.insert(spec.name.clone()) {
+                bail!("node {} is specified twice", spec.name);
+            }
+            // Paths are relative to the topology file
+            if let Some(ref mut t) = spec.tun2proxy {
+                *t = base.join(&t).canonicalize()?;
+            }
+        }
+        topo.order()?;
+        Ok(topo)
+    }
+    /// Sorts the nodes so that out nodes come first
+    fn order(&mut self) -> Result<()> {
+        let index: HashMap<_, _> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.name.clone(), i))
+            .collect();
+        let mut sorted = Vec::with_capacity(self.nodes.len());
+        let mut state = vec![0u8; self.nodes.len()];
+        fn visit(
+            i: usize,
+            nodes: &[NodeSpec],
+            index: &HashMap<String, usize>,
+            state: &mut [u8],
+            sorted: &mut Vec<usize>,
+        ) -> Result<()> {
+            match state[i] {
+                2 => return Ok(()),
+                1 => bail!("node {} routes through itself", nodes[i].name),
+                _ => (),
+            }
+            state[i] = 1;
+            if let Some(ref out) = nodes[i].out {
+                let o = index.get(out).ok_or(anyhow!(
+                    "out node {} of {} is not in the topology",
+                    out,
+                    nodes[i].name
+                ))?;
+                visit(*o, nodes, index, state, sorted)?;
+            }
+            state[i] = 2;
+            sorted.push(i);
+            Ok(())
+        }
+        for i in 0..self.nodes.len() {
+            visit(i, &self.nodes, &index, &mut state, &mut sorted)?;
+        }
+        let mut nodes: Vec<_> = self.nodes.drain(..).map(Some).collect();
+        self.nodes = sorted
+            .into_iter()
+            .map(|i| nodes[i].take().unwrap())
+            .collect();
+        Ok(())
+    }
+    /// Compare with the graph, live links, and units on disk.
+    /// nl: Netlink of the NetNS nsproxy runs in, which is the default out node.
+    /// Links in other out nodes are looked up in their NetNS.
+    async fn diff(
+        &self,
+        graphs: &Graphs,
+        ctx: &NSGroup<ExactNS>,
+        nl: &NLDriver,
+        serv: &Systemd,
+    ) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+        // Nodes that will be (re)created. Their dependents have to follow.
+        let mut fresh: HashSet<&str> = HashSet::new();
+        let wanted: HashSet<&str> = self.nodes.iter().map(|n| n.name.as_str()).collect();
+        for (name, ix) in graphs.name.iter() {
+            // Nodes made by hand are left alone
+            let applied = graphs.data[*ix]
+                .as_ref()
+                .is_some_and(|n| n.applied.is_some());
+            if applied && !wanted.contains(name.as_str()) {
+                steps.push(Step::Remove(*ix, name.clone()));
+            }
+        }
+        let root = graphs.map.get(&ctx.net.must()?.unique);
+        for spec in &self.nodes {
+            let ix = match graphs.name.get_by_left(&spec.name) {
+                Some(ix) => *ix,
+                None => {
+                    fresh.insert(&spec.name);
+                    steps.push(Step::Create(spec.clone()));
+                    continue;
+                }
+            };
+            let out_fresh = spec
+                .out
+                .as_ref()
+                .is_some_and(|o| fresh.contains(o.as_str()));
+            let out = match &spec.out {
+                Some(o) => graphs.name.get_by_left(o),
+                None => root,
+            };
+            let (node, deps) = graphs.nodewdeps(ix)?;
+            let mut tun = None;
+            let mut veth = false;
+            let mut out_match = true;
+            let mut units = true;
+            for dep in &deps {
+                if Some(&dep.dst.id) != out {
+                    out_match = false;
+                }
+                match dep.edge.item {
+                    Relation::Veth(ve) => {
+                        // The end in the out node
+                        let end = ve.key.link(LinkAB::B);
+                        veth |= if Some(&dep.dst.id) == root {
+                            nl.links.contains_key(&end)
+                        } else {
+                            match nl_in_ns(dep.dst.item.main.net.must()?).await {
+                                Ok(h) => {
+                                    let mut nlo = NLDriver::new(h);
+                                    nlo.fill().await?;
+                                    nlo.links.contains_key(&end)
+                                }
+                                // The out node is gone with it
+                                Err(_) => false,
+                            }
+                        };
+                    }
+                    rel => {
+                        if let Some(FDRecver::TUN2Proxy(path)) = rel.fd_recver() {
+                            tun = Some(path);
+                            let s2t = Socks2TUN::new(path, dep.edge.id)?;
+                            units &= serv.unit_exists(&s2t.service()?);
+                            units &= serv.unit_exists(&s2t.sockunit()?);
+                        }
+                    }
+                }
+            }
+            if tun.is_some() {
+                units &= serv.unit_exists(&node.service()?);
+            }
+            let assoc = if let Some(a) = &spec.associated {
+                let mut nlc = NLDriver::new(nl_in_ns(node.item.main.net.must()?).await?);
+                nlc.fill().await?;
+                let renamed: LinkKey = format!("{}_", a).parse()?;
+                nlc.links.contains_key(&renamed)
+            } else {
+                true
+            };
+            // Nodes made by hand are taken as launched with the defaults
+            let launch = spec.same_launch(&node.item.applied.clone().unwrap_or_default());
+            if out_fresh || !out_match || veth != spec.veth || !assoc || !launch {
+                fresh.insert(&spec.name);
+                steps.push(Step::Recreate(ix, spec.clone()));
+            } else if tun != spec.tun2proxy.as_ref() {
+                steps.push(Step::TUN2Proxy(ix, spec.clone()));
+            } else if !units {
+                steps.push(Step::WriteUnits(ix));
+            }
+        }
+        Ok(steps)
+    }
+}
+
+#[public]
+impl NodeSpec {
+    /// Options that take effect only when the node is created
+    fn same_launch(&self, other: &Self) -> bool {
+        (&self.cmd, self.uid, self.userns, self.assoc_ip)
+            == (&other.cmd, other.uid, other.userns, other.assoc_ip)
+    }
+    /// The `nsproxy new` invocation that creates this node
+    fn command(&self, exe: &Path) -> Command {
+        let mut cmd = Command::new(exe);
+        cmd.arg("new").arg("--mount").args(["--name", &self.name]);
+        if let Some(t) = &self.tun2proxy {
+            cmd.arg("--tun2proxy").arg(t);
+        }
+        if let Some(o) = &self.out {
+            cmd.args(["--out", o]);
+        }
+        if self.veth {
+            cmd.arg("--veth");
+        }
+        if let Some(a) = &self.associated {
+            cmd.args(["--associated", a]);
+        }
+        if let Some(ip) = &self.assoc_ip {
+            cmd.args(["--assoc-ip", &ip.to_string()]);
+        }
+        if let Some(u) = &self.uid {
+            cmd.args(["--uid", &u.to_string()]);
+        }
+        if self.userns {
+            cmd.arg("--userns");
+        }
+        // The node persists as it's mounted
+        cmd.arg(self.cmd.as_deref().unwrap_or("true"));
+        cmd.stdin(Stdio::null());
+        cmd
+    }
+}
+
+#[cfg(test)]
+fn spec(name: &str, out: Option<&str>) -> NodeSpec {
+    NodeSpec {
+        name: name.to_owned(),
+        out: out.map(str::to_owned),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn order_cycle() {
+    let mut topo = Topology {
+        nodes: vec![spec("b", Some("a")), spec("c", None), spec("a", None)],
+    };
+    topo.order().unwrap();
+    let names: Vec<_> = topo.nodes.iter().map(|n| n.name.as_str()).collect();
+    assert_eq!(names, ["a", "b", "c"]);
+    let mut topo = Topology {
+        nodes: vec![spec("x", Some("y")), spec("y", Some("x"))],
+    };
+    assert!(topo.order().is_err());
+}
+
+/// Removals come first, and dependents of recreated nodes are recreated after them.
+/// Nodes made by hand are not removed, and a changed command recreates the node
+#[test]
+fn diff_steps() -> Result<()> {
+    use crate::{
+        data::{ObjectNode, PidPath},
+        paths::PathState,
+        plan::Recorder,
+    };
+    use netlink_ops::netlink::NLHandle;
+
+    let mut graphs = Graphs::default();
+    for name in ["gone", "a", "b", "d", "manual"] {
+        let ix = graphs.data.add_node(Some(ObjectNode {
+            name: Some(name.to_owned()),
+            main: NSGroup::default(),
+            root: false,
+            identity: None,
+            applied: (name != "manual").then(|| spec(name, None)),
+        }));
+        graphs.name.insert(name.to_owned(), ix);
+    }
+    let mut a = spec("a", None);
+    a.veth = true;
+    let mut d = spec("d", None);
+    d.cmd = Some("sh".to_owned());
+    let mut topo = Topology {
+        nodes: vec![spec("b", Some("a")), spec("c", None), a, d],
+    };
+    topo.order()?;
+    let ctx = NSGroup::proc_path(PidPath::Selfproc, None)?;
+    let serv = Systemd::planned(&PathState::default(1000)?, false, Recorder::default())?;
+    let steps = crate::blockon::block_on(async {
+        let nl = NLDriver::new(NLHandle::new_self_proc_tokio()?);
+        topo.diff(&graphs, &ctx, &nl, &serv).await
+    })??;
+    let steps: Vec<_> = steps
+        .iter()
+        .map(|s| match s {
+            Step::Create(spec) => format!("create {}", spec.name),
+            Step::Recreate(_, spec) => format!("recreate {}", spec.name),
+            Step::Remove(_, name) => format!("remove {}", name),
+            s => s.to_string(),
+        })
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            "remove gone",
+            "recreate a",
+            "recreate b",
+            "create c",
+            "recreate d"
+        ]
+    );
+    Ok(())
+}